@@ -1,26 +1,93 @@
-use crate::header::Header;
-use crate::toc::Toc;
-use crate::*;
-use failure::Error;
-use libflate::zlib::Decoder;
+use crate::builder::{ArchiveBuilder, Heap};
+use crate::checksum::{self, to_hex, Checksum};
+use crate::encoding::Encoding;
+use crate::extract::Extractor;
+use crate::header::{ChecksumAlg, Header};
+use crate::limits::ReadLimits;
+use crate::toc::{self, ExtendedAttribute, File, Toc};
+use crate::verify::{Failure, Verifier};
+use crate::Error;
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+use std::cell::{RefCell, RefMut};
 use std::fmt;
-use std::io::SeekFrom;
-use std::io::{Read, Seek};
+#[cfg(feature = "mmap")]
+use std::io::Cursor;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+#[cfg(feature = "mmap")]
+use std::sync::Arc;
+use thiserror::Error;
+use xmltree::Element;
 
-#[derive(Debug, Clone)]
-pub struct Archive {
+#[derive(Error, Debug)]
+pub enum Errors {
+    #[error("File ‘{0}’ has no data.")]
+    NoData(String),
+    #[error("Archive reader is already in use.")]
+    ReaderBusy,
+    #[error("Toc checksum has {0} bytes, expected {1}.")]
+    TocChecksumSize(usize, usize),
+    #[error("Toc checksum mismatch: expected {0}, got {1}.")]
+    TocChecksumMismatch(String, String),
+    #[error("{1} bytes at heap offset {0} are past the end of the archive.")]
+    HeapOutOfBounds(u64, u64),
+    #[error("‘{0}’ already exists in the archive.")]
+    AlreadyExists(String),
+}
+
+#[derive(Debug)]
+pub struct Archive<R> {
     header: Header,
     toc: Toc,
+    heap_offset: u64,
+    limits: ReadLimits,
+    reader: RefCell<R>,
+    /// The mapping the reader reads from, for archives opened with
+    /// `open_mmap`.
+    #[cfg(feature = "mmap")]
+    mmap: Option<Arc<Mmap>>,
 }
 
-impl Archive {
-    pub fn from_read<T: Read>(reader: &mut T) -> Result<Archive, Error> {
-        let header = Header::from_read(reader)?;
+impl<R: Read> Archive<R> {
+    /// Read the header and toc of an archive with the default limits.
+    pub fn from_read(reader: R) -> Result<Archive<R>, Error> {
+        Self::from_read_with_limits(reader, ReadLimits::default())
+    }
+
+    /// Read the header and toc of an archive. The limits are also enforced
+    /// when extracting from it.
+    pub fn from_read_with_limits(mut reader: R, limits: ReadLimits) -> Result<Archive<R>, Error> {
+        let header = Header::from_read_with_limits(&mut reader, &limits)?;
 
-        // TODO: verify that only header.toc_length_compressed bytes were read.
-        let toc = Toc::from_read(reader, header.toc_length_uncompressed as usize)?;
+        let mut toc_reader = (&mut reader).take(header.toc_length_compressed);
+        let toc = Toc::from_read_with_limits(
+            &mut toc_reader,
+            header.toc_length_uncompressed as usize,
+            &limits,
+        )?;
 
-        Ok(Archive { header, toc })
+        // the heap starts right after the toc, so it has to end where the
+        // header says it does.
+        let left = toc_reader.limit();
+        if left != 0 {
+            let read = header.toc_length_compressed - left;
+            return Err(
+                toc::Errors::CompressedLengthMismatch(header.toc_length_compressed, read).into(),
+            );
+        }
+
+        let heap_offset = header.size as u64 + header.toc_length_compressed;
+
+        Ok(Archive {
+            header,
+            toc,
+            heap_offset,
+            limits,
+            reader: RefCell::new(reader),
+            #[cfg(feature = "mmap")]
+            mmap: None,
+        })
     }
 
     pub fn header(&self) -> &Header {
@@ -30,10 +97,330 @@ impl Archive {
     pub fn toc(&self) -> &Toc {
         &self.toc
     }
+
+    pub fn limits(&self) -> &ReadLimits {
+        &self.limits
+    }
+
+    /// The heap of a memory mapped archive, `None` for other readers.
+    pub(crate) fn mapped_heap(&self) -> Option<&[u8]> {
+        #[cfg(feature = "mmap")]
+        if let Some(map) = &self.mmap {
+            return Some(map.get(self.heap_offset as usize..).unwrap_or(&[]));
+        }
+        None
+    }
+
+    /// Offset of the heap from the start of the archive. File data offsets
+    /// are relative to this.
+    pub fn heap_offset(&self) -> u64 {
+        self.heap_offset
+    }
+
+    /// Borrow the underlying reader, for reading the heap front to back.
+    pub(crate) fn reader(&self) -> Result<RefMut<'_, R>, Error> {
+        Ok(self
+            .reader
+            .try_borrow_mut()
+            .map_err(|_| Errors::ReaderBusy)?)
+    }
+
+    /// Give back the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
 }
 
-impl std::fmt::Display for Archive {
+impl<R: Read + Seek> Archive<R> {
+    /// Read the (still encoded) data of a file from the heap. Hardlinks
+    /// read the data of their original.
+    ///
+    /// Only one reader can be open at a time.
+    pub fn open_data(&self, file: &File) -> Result<HeapReader<'_, R>, Error> {
+        let data = file
+            .data()?
+            .ok_or_else(|| Errors::NoData(file.path.display().to_string()))?;
+        self.open_heap(data.offset, data.length)
+    }
+
+    /// Returns a reader yielding the decoded value of an extended attribute.
+    pub fn open_xattr(&self, xattr: &ExtendedAttribute) -> Result<Box<dyn Read + '_>, Error> {
+        let data = &xattr.data;
+        data.encoding
+            .decoder(self.open_heap(data.offset, data.length)?)
+    }
+
+    /// Read `length` bytes from the heap, starting at `offset`.
+    ///
+    /// Only one reader can be open at a time.
+    pub fn open_heap(&self, offset: u64, length: u64) -> Result<HeapReader<'_, R>, Error> {
+        let mut reader = self
+            .reader
+            .try_borrow_mut()
+            .map_err(|_| Errors::ReaderBusy)?;
+        reader.seek(SeekFrom::Start(self.heap_offset + offset))?;
+
+        Ok(HeapReader {
+            reader,
+            remaining: length,
+        })
+    }
+
+    /// Check the checksum of the compressed toc against the one stored in
+    /// the heap. Archives without a toc checksum always pass.
+    pub fn verify_toc_checksum(&self) -> Result<(), Error> {
+        if self.header.checksum_alg == ChecksumAlg::None {
+            return Ok(());
+        }
+
+        let mut checksum = Checksum::new(&self.header.checksum_alg)?;
+        {
+            let mut reader = self
+                .reader
+                .try_borrow_mut()
+                .map_err(|_| Errors::ReaderBusy)?;
+            reader.seek(SeekFrom::Start(self.header.size as u64))?;
+            std::io::copy(
+                &mut (&mut *reader).take(self.header.toc_length_compressed),
+                &mut checksum,
+            )?;
+        }
+
+        let size = self.toc.checksum_size()?;
+        if size != checksum.size() {
+            return Err(Errors::TocChecksumSize(size, checksum.size()).into());
+        }
+
+        let mut expected = Vec::new();
+        self.open_heap(self.toc.checksum_offset()? as u64, size as u64)?
+            .read_to_end(&mut expected)?;
+
+        let actual = checksum.finish();
+        if actual != expected {
+            return Err(Errors::TocChecksumMismatch(to_hex(&expected), to_hex(&actual)).into());
+        }
+
+        Ok(())
+    }
+
+    /// Check the data of every file against its archived and extracted
+    /// checksums, returning all mismatches.
+    pub fn verify_files(&self) -> Result<Vec<Failure>, Error> {
+        Verifier::new(self).verify()
+    }
+
+    /// Write a copy of the archive with the entries of `builder` added.
+    /// Existing data is copied as it is and new data goes after it, so only
+    /// the new files are compressed. Directories already in the archive get
+    /// the new entries below them added to their children.
+    pub fn append<W: Write>(&self, builder: &ArchiveBuilder, writer: &mut W) -> Result<(), Error> {
+        let heap_length = {
+            let mut reader = self.reader()?;
+            reader.seek(SeekFrom::End(0))? - self.heap_offset
+        };
+
+        let mut xar = self.toc.data().clone();
+        let toc = xar.get_mut_child("toc").ok_or(toc::Errors::NoTocElement)?;
+        let mut heap = Heap::new(heap_length)?;
+        for file in builder.file_elements(&mut heap, max_id(toc))? {
+            merge(toc, file, Path::new(""))?;
+        }
+
+        let mut toc_data = Vec::new();
+        xar.write(&mut toc_data)?;
+        let toc_compressed = Encoding::Gzip.encode(&toc_data)?;
+
+        let mut header = self.header.clone();
+        header.toc_length_compressed = toc_compressed.len() as u64;
+        header.toc_length_uncompressed = toc_data.len() as u64;
+        header.write(writer)?;
+        writer.write_all(&toc_compressed)?;
+
+        // the checksum of the toc is the only existing data that changes.
+        if self.header.checksum_alg == ChecksumAlg::None {
+            std::io::copy(&mut self.open_heap(0, heap_length)?, writer)?;
+        } else {
+            let checksum = checksum::digest(&self.header.checksum_alg, &toc_compressed)?;
+            let (offset, size) = (self.toc.checksum_offset()?, self.toc.checksum_size()?);
+            if size != checksum.len() {
+                return Err(Errors::TocChecksumSize(size, checksum.len()).into());
+            }
+            let (offset, size) = (offset as u64, size as u64);
+            let rest = heap_length
+                .checked_sub(offset + size)
+                .ok_or(Errors::HeapOutOfBounds(offset, size))?;
+
+            std::io::copy(&mut self.open_heap(0, offset)?, writer)?;
+            writer.write_all(&checksum)?;
+            std::io::copy(&mut self.open_heap(offset + size, rest)?, writer)?;
+        }
+
+        heap.copy_to(writer)?;
+        Ok(())
+    }
+
+    /// Extract all files into `dest`.
+    pub fn extract_all(&self, dest: &Path) -> Result<(), Error> {
+        Extractor::new(self, dest).extract()
+    }
+
+    /// Extract the given paths (and everything below them) into `dest`.
+    pub fn extract<P: AsRef<Path>>(&self, dest: &Path, paths: &[P]) -> Result<(), Error> {
+        Extractor::new(self, dest).paths(paths).extract()
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Archive<Cursor<SharedMmap>> {
+    /// Map the archive at `path` into memory, with the default limits.
+    pub fn open_mmap<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::open_mmap_with_limits(path, ReadLimits::default())
+    }
+
+    /// Map the archive at `path` into memory, so that file data can be
+    /// borrowed straight out of the heap.
+    ///
+    /// The file must not be changed while it is mapped, the data read from
+    /// it would change as well.
+    pub fn open_mmap_with_limits<P: AsRef<Path>>(
+        path: P,
+        limits: ReadLimits,
+    ) -> Result<Self, Error> {
+        let file = std::fs::File::open(path)?;
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        let mut archive =
+            Self::from_read_with_limits(Cursor::new(SharedMmap(map.clone())), limits)?;
+        archive.mmap = Some(map);
+        Ok(archive)
+    }
+
+    /// The whole heap, without copying.
+    pub fn heap(&self) -> &[u8] {
+        self.mapped_heap().unwrap_or(&[])
+    }
+
+    /// `length` bytes of the heap starting at `offset`, without copying.
+    pub fn heap_slice(&self, offset: u64, length: u64) -> Result<&[u8], Error> {
+        let heap = self.heap();
+        offset
+            .checked_add(length)
+            .filter(|end| *end <= heap.len() as u64)
+            .map(|end| &heap[offset as usize..end as usize])
+            .ok_or_else(|| Errors::HeapOutOfBounds(offset, length).into())
+    }
+
+    /// The data of a file. Data stored as is is borrowed from the heap,
+    /// anything else is decoded from it while reading.
+    pub fn data(&self, file: &File) -> Result<Data<'_>, Error> {
+        let data = file
+            .data()?
+            .ok_or_else(|| Errors::NoData(file.path.display().to_string()))?;
+        let raw = self.heap_slice(data.offset, data.length)?;
+        match data.encoding {
+            Encoding::OctetStream => Ok(Data::Slice(raw)),
+            encoding => Ok(Data::Decoder(encoding.decoder(raw)?)),
+        }
+    }
+}
+
+/// A memory mapping shared by the reader of an archive and its heap.
+#[cfg(feature = "mmap")]
+#[derive(Debug, Clone)]
+pub struct SharedMmap(Arc<Mmap>);
+
+#[cfg(feature = "mmap")]
+impl AsRef<[u8]> for SharedMmap {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Data of a file in a memory mapped archive.
+#[cfg(feature = "mmap")]
+pub enum Data<'a> {
+    /// Stored without encoding, straight out of the heap.
+    Slice(&'a [u8]),
+    /// Decoded from the heap while reading.
+    Decoder(Box<dyn Read + 'a>),
+}
+
+#[cfg(feature = "mmap")]
+impl<'a> Read for Data<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Data::Slice(slice) => slice.read(buf),
+            Data::Decoder(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// Highest id of the files in or below `element`.
+fn max_id(element: &Element) -> u64 {
+    element
+        .children
+        .iter()
+        .filter(|child| child.name == "file")
+        .map(|file| {
+            let id = file
+                .attributes
+                .get("id")
+                .and_then(|id| id.parse().ok())
+                .unwrap_or(0);
+            id.max(max_id(file))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Add a `<file>` element to the children of `parent`. A directory that
+/// exists already is kept, with the children of the new one merged into it.
+fn merge(parent: &mut Element, file: Element, path: &Path) -> Result<(), Error> {
+    let text = |element: &Element, name: &str| {
+        element.get_child(name).and_then(|child| child.text.clone())
+    };
+    let name = text(&file, "name").unwrap_or_default();
+    let path = path.join(&name);
+
+    let existing = parent
+        .children
+        .iter_mut()
+        .find(|child| child.name == "file" && text(child, "name").as_ref() == Some(&name));
+    match existing {
+        None => parent.children.push(file),
+        Some(existing) => {
+            let directory = Some("directory".to_string());
+            if text(existing, "type") != directory || text(&file, "type") != directory {
+                return Err(Errors::AlreadyExists(path.display().to_string()).into());
+            }
+            for child in file.children.into_iter().filter(|c| c.name == "file") {
+                merge(existing, child, &path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl<R> std::fmt::Display for Archive<R> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}\n{}", self.header, self.toc)
     }
 }
+
+/// Reader over a region of the heap.
+pub struct HeapReader<'a, R> {
+    reader: RefMut<'a, R>,
+    remaining: u64,
+}
+
+impl<'a, R: Read> Read for HeapReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let max = std::cmp::min(buf.len() as u64, self.remaining) as usize;
+        let read = self.reader.read(&mut buf[..max])?;
+        if read == 0 && max > 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
@@ -0,0 +1,914 @@
+use crate::checksum::{self, Checksum, ChecksumReader, ChecksumWriter};
+use crate::encoding::Encoding;
+use crate::header::{ChecksumAlg, Header};
+use crate::toc::{Device, FileType, Hardlink, LinkType};
+use crate::xattr;
+use crate::Error;
+use chrono::{NaiveDateTime, Utc};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use tempfile::SpooledTempFile;
+use thiserror::Error;
+use xmltree::Element;
+
+/// Format of timestamps of files in the toc.
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+/// Format of the creation time of the toc.
+const CREATION_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Encoded data worker threads keep in memory before moving it to disk.
+const SPOOL_SIZE: usize = 1 << 20;
+
+#[derive(Error, Debug)]
+pub enum Errors {
+    #[error("Path ‘{0}’ has no file name.")]
+    NoFileName(String),
+    #[error("Path ‘{0}’ is not valid UTF-8.")]
+    InvalidName(String),
+    #[error("Cannot add ‘{0}’, parent is not a directory.")]
+    NotADirectory(String),
+    #[error("Cannot add ‘{0}’, unsupported file type.")]
+    UnsupportedFileType(String),
+    #[error("Timestamp {0} is out of range.")]
+    TimestampOutOfRange(i64),
+}
+
+/// Metadata of an entry, as far as it is known.
+#[derive(Debug, Clone, Default)]
+struct Metadata {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    ctime: Option<NaiveDateTime>,
+    mtime: Option<NaiveDateTime>,
+    atime: Option<NaiveDateTime>,
+    inode: Option<u64>,
+    deviceno: Option<u64>,
+    nlink: Option<u64>,
+    /// Extended attributes, by name.
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl Metadata {
+    #[cfg(unix)]
+    fn from_fs(metadata: &fs::Metadata) -> Metadata {
+        use std::os::unix::fs::MetadataExt;
+        Metadata {
+            mode: Some(metadata.mode() & 0o7777),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            // times chrono can't represent are left out.
+            ctime: NaiveDateTime::from_timestamp_opt(metadata.ctime(), 0),
+            mtime: NaiveDateTime::from_timestamp_opt(metadata.mtime(), 0),
+            atime: NaiveDateTime::from_timestamp_opt(metadata.atime(), 0),
+            inode: Some(metadata.ino()),
+            deviceno: Some(metadata.dev()),
+            nlink: Some(metadata.nlink()),
+            ..Metadata::default()
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from_fs(_metadata: &fs::Metadata) -> Metadata {
+        Metadata::default()
+    }
+
+    fn with_mode(mode: u32) -> Metadata {
+        Metadata {
+            mode: Some(mode),
+            ..Metadata::default()
+        }
+    }
+}
+
+/// Where the data of a file comes from.
+#[derive(Debug, Clone)]
+enum Source {
+    Path(PathBuf),
+    Buffer(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    File(Source),
+    Directory(Vec<Entry>),
+    Symlink(String, LinkType),
+    /// Fifos, sockets and devices, which have no data.
+    Special(FileType, Option<Device>),
+}
+
+/// Data to be stored in the heap.
+pub(crate) enum Blob<'b> {
+    Path(&'b Path),
+    Buffer(&'b [u8]),
+}
+
+impl Blob<'_> {
+    fn open(&self) -> Result<Box<dyn Read + '_>, Error> {
+        match self {
+            Blob::Path(path) => Ok(Box::new(fs::File::open(path)?)),
+            Blob::Buffer(buffer) => Ok(Box::new(*buffer)),
+        }
+    }
+}
+
+/// Data compressed into the heap, with its checksums.
+pub(crate) struct Encoded {
+    pub(crate) size: u64,
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+    pub(crate) extracted_checksum: Vec<u8>,
+    pub(crate) archived_checksum: Vec<u8>,
+}
+
+/// Encoded data, or the error encoding it, as handed over in order.
+type EncodedIter<'i> = dyn Iterator<Item = Result<Encoded, Error>> + 'i;
+
+/// Heap data being written, which starts at `offset` of the heap. It is
+/// kept in a temporary file until the toc is written.
+pub(crate) struct Heap {
+    file: BufWriter<fs::File>,
+    length: u64,
+    offset: u64,
+}
+
+impl Heap {
+    pub(crate) fn new(offset: u64) -> Result<Heap, Error> {
+        Ok(Heap {
+            file: BufWriter::new(tempfile::tempfile()?),
+            length: 0,
+            offset,
+        })
+    }
+
+    /// Offset in the heap of the next data appended.
+    pub(crate) fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+
+    /// Whether `length` bytes at offsets `a` and `b` of the heap, both
+    /// appended earlier, are the same.
+    pub(crate) fn same(&mut self, a: u64, b: u64, length: u64) -> Result<bool, Error> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        let (mut left, mut right) = ([0; 8192], [0; 8192]);
+        let mut compared = 0;
+        let mut same = true;
+        while same && compared < length {
+            let chunk = (length - compared).min(left.len() as u64) as usize;
+            file.seek(SeekFrom::Start(a - self.offset + compared))?;
+            file.read_exact(&mut left[..chunk])?;
+            file.seek(SeekFrom::Start(b - self.offset + compared))?;
+            file.read_exact(&mut right[..chunk])?;
+            same = left[..chunk] == right[..chunk];
+            compared += chunk as u64;
+        }
+        file.seek(SeekFrom::End(0))?;
+        Ok(same)
+    }
+
+    /// Drop everything appended from `offset` of the heap on.
+    pub(crate) fn truncate(&mut self, offset: u64) -> Result<(), Error> {
+        self.file.flush()?;
+        self.length = offset - self.offset;
+        let file = self.file.get_mut();
+        file.set_len(self.length)?;
+        file.seek(SeekFrom::Start(self.length))?;
+        Ok(())
+    }
+
+    /// Copy everything appended to `writer`.
+    pub(crate) fn copy_to<W: Write>(self, writer: &mut W) -> Result<(), Error> {
+        let mut file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut file, writer)?;
+        Ok(())
+    }
+}
+
+impl Write for Heap {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.length += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A file, directory or link to be written into the archive.
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    kind: Kind,
+    metadata: Metadata,
+}
+
+/// Builds a new archive from files on disk or from memory.
+///
+/// Nothing is read from disk until the archive is written.
+#[derive(Debug, Clone)]
+pub struct ArchiveBuilder {
+    entries: Vec<Entry>,
+    checksum_alg: ChecksumAlg,
+    encoding: Encoding,
+    jobs: usize,
+    /// Set for reproducible archives, no timestamp is later than this.
+    source_date_epoch: Option<NaiveDateTime>,
+}
+
+impl ArchiveBuilder {
+    pub fn new() -> Self {
+        ArchiveBuilder {
+            entries: Vec::new(),
+            checksum_alg: ChecksumAlg::SHA1,
+            encoding: Encoding::Gzip,
+            jobs: 1,
+            source_date_epoch: None,
+        }
+    }
+
+    /// Set the checksum algorithm used for the toc and all files.
+    pub fn checksum_alg(&mut self, alg: ChecksumAlg) -> &mut Self {
+        self.checksum_alg = alg;
+        self
+    }
+
+    /// Set the encoding used for file data.
+    pub fn encoding(&mut self, encoding: Encoding) -> &mut Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Compress and checksum data on this many threads. The archive is the
+    /// same no matter how many are used.
+    pub fn jobs(&mut self, jobs: usize) -> &mut Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// Write the same archive from the same files every time: entries and
+    /// extended attributes are sorted by name, timestamps are clamped to
+    /// `source_date_epoch`, which is also the creation time of the toc,
+    /// owners are root and inodes and device numbers are left out.
+    ///
+    /// Fails if `source_date_epoch` is outside of the range of dates that
+    /// can be represented.
+    pub fn reproducible(&mut self, source_date_epoch: i64) -> Result<&mut Self, Error> {
+        let epoch = NaiveDateTime::from_timestamp_opt(source_date_epoch, 0)
+            .ok_or(Errors::TimestampOutOfRange(source_date_epoch))?;
+        self.source_date_epoch = Some(epoch);
+        Ok(self)
+    }
+
+    /// Add a file, directory or symlink from disk, recursing into
+    /// directories. It is stored under its own (relative) path.
+    pub fn add_path<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        self.add_path_as(path, path)
+    }
+
+    /// Add a file, directory or symlink from disk under a different name.
+    pub fn add_path_as<P: AsRef<Path>, N: AsRef<Path>>(
+        &mut self,
+        path: P,
+        name: N,
+    ) -> Result<(), Error> {
+        let entry = Self::entry_from_path(path.as_ref(), name.as_ref())?;
+        self.insert(name.as_ref(), entry)
+    }
+
+    /// Add a file with the given contents.
+    pub fn add_data<N: AsRef<Path>>(&mut self, name: N, data: Vec<u8>) -> Result<(), Error> {
+        let entry = Entry {
+            name: Self::file_name(name.as_ref())?,
+            kind: Kind::File(Source::Buffer(data)),
+            metadata: Metadata::with_mode(0o644),
+        };
+        self.insert(name.as_ref(), entry)
+    }
+
+    /// Add an empty directory.
+    pub fn add_directory<N: AsRef<Path>>(&mut self, name: N) -> Result<(), Error> {
+        let entry = Entry {
+            name: Self::file_name(name.as_ref())?,
+            kind: Kind::Directory(Vec::new()),
+            metadata: Metadata::with_mode(0o755),
+        };
+        self.insert(name.as_ref(), entry)
+    }
+
+    /// Add a symlink pointing to target.
+    pub fn add_symlink<N: AsRef<Path>>(&mut self, name: N, target: &str) -> Result<(), Error> {
+        let entry = Entry {
+            name: Self::file_name(name.as_ref())?,
+            kind: Kind::Symlink(target.into(), LinkType::File),
+            metadata: Metadata::with_mode(0o755),
+        };
+        self.insert(name.as_ref(), entry)
+    }
+
+    fn entry_from_path(path: &Path, name: &Path) -> Result<Entry, Error> {
+        let metadata = fs::symlink_metadata(path)?;
+        let ftype = metadata.file_type();
+
+        let kind = if ftype.is_file() {
+            Kind::File(Source::Path(path.to_path_buf()))
+        } else if ftype.is_dir() {
+            let mut children = Vec::new();
+            let mut dir: Vec<_> = fs::read_dir(path)?.collect::<Result<_, _>>()?;
+            dir.sort_by_key(|entry| entry.file_name());
+            for child in dir {
+                let child_name = name.join(child.file_name());
+                children.push(Self::entry_from_path(&child.path(), &child_name)?);
+            }
+            Kind::Directory(children)
+        } else if ftype.is_symlink() {
+            let target = fs::read_link(path)?;
+            let target = target
+                .to_str()
+                .ok_or_else(|| Errors::InvalidName(target.display().to_string()))?;
+            let link_type = match fs::metadata(path) {
+                Ok(ref m) if m.is_dir() => LinkType::Directory,
+                Ok(_) => LinkType::File,
+                Err(_) => LinkType::Broken,
+            };
+            Kind::Symlink(target.into(), link_type)
+        } else {
+            Self::special_kind(&metadata)
+                .ok_or_else(|| Errors::UnsupportedFileType(path.display().to_string()))?
+        };
+
+        let mut metadata = Metadata::from_fs(&metadata);
+        metadata.xattrs = match xattr::list(path) {
+            Err(ref e) if xattr::is_unsupported(e) => Vec::new(),
+            result => result?,
+        };
+
+        Ok(Entry {
+            name: Self::file_name(name)?,
+            kind,
+            metadata,
+        })
+    }
+
+    #[cfg(unix)]
+    fn special_kind(metadata: &fs::Metadata) -> Option<Kind> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+        let ftype = metadata.file_type();
+        let rdev = metadata.rdev() as libc::dev_t;
+        let device = Device {
+            major: libc::major(rdev) as u32,
+            minor: libc::minor(rdev) as u32,
+        };
+
+        if ftype.is_fifo() {
+            Some(Kind::Special(FileType::Fifo, None))
+        } else if ftype.is_socket() {
+            Some(Kind::Special(FileType::Socket, None))
+        } else if ftype.is_char_device() {
+            Some(Kind::Special(FileType::CharacterSpecial, Some(device)))
+        } else if ftype.is_block_device() {
+            Some(Kind::Special(FileType::BlockSpecial, Some(device)))
+        } else {
+            None
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn special_kind(_metadata: &fs::Metadata) -> Option<Kind> {
+        None
+    }
+
+    /// Last normal component of a path, which is what it is stored as.
+    fn file_name(path: &Path) -> Result<String, Errors> {
+        let name = Self::components(path)
+            .pop()
+            .ok_or_else(|| Errors::NoFileName(path.display().to_string()))?;
+        Ok(name)
+    }
+
+    /// Normal components of a path, dropping roots, prefixes and `.`. A
+    /// `..` drops the preceding component.
+    fn components(path: &Path) -> Vec<String> {
+        let mut components = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => components.push(name.to_string_lossy().into_owned()),
+                Component::ParentDir => {
+                    components.pop();
+                }
+                _ => {}
+            }
+        }
+        components
+    }
+
+    /// Insert entry at the position given by name, creating any missing
+    /// parent directories.
+    fn insert(&mut self, name: &Path, entry: Entry) -> Result<(), Error> {
+        let mut components = Self::components(name);
+        components.pop();
+
+        let mut entries = &mut self.entries;
+        for component in components {
+            let pos = match entries.iter().position(|e| e.name == component) {
+                Some(pos) => pos,
+                None => {
+                    entries.push(Entry {
+                        name: component,
+                        kind: Kind::Directory(Vec::new()),
+                        metadata: Metadata::with_mode(0o755),
+                    });
+                    entries.len() - 1
+                }
+            };
+
+            entries = match &mut entries[pos].kind {
+                Kind::Directory(children) => children,
+                _ => return Err(Errors::NotADirectory(name.display().to_string()).into()),
+            };
+        }
+
+        entries.push(entry);
+        Ok(())
+    }
+
+    /// Write the archive. File data is read, compressed and checksummed
+    /// here, so this can fail if files on disk have gone missing.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        // the checksum of the toc goes in front of the data.
+        let checksum_size = Checksum::new(&self.checksum_alg)?.size();
+        let mut heap = Heap::new(checksum_size as u64)?;
+
+        let mut toc = Element::new("toc");
+        toc.children.push(self.creation_time_element());
+        toc.children.push(self.checksum_element(checksum_size));
+        toc.children.extend(self.file_elements(&mut heap, 0)?);
+
+        let mut xar = Element::new("xar");
+        xar.children.push(toc);
+
+        let mut toc_data = Vec::new();
+        xar.write(&mut toc_data)?;
+
+        let toc_compressed = Encoding::Gzip.encode(&toc_data)?;
+
+        let toc_checksum = checksum::digest(&self.checksum_alg, &toc_compressed)?;
+
+        let header = Header::new(
+            toc_compressed.len() as u64,
+            toc_data.len() as u64,
+            self.checksum_alg.clone(),
+        );
+
+        header.write(writer)?;
+        writer.write_all(&toc_compressed)?;
+        writer.write_all(&toc_checksum)?;
+        heap.copy_to(writer)?;
+
+        Ok(())
+    }
+
+    /// The `<file>` elements of all entries, with their data appended to
+    /// the heap. Ids are given out starting after `last_id`.
+    pub(crate) fn file_elements(
+        &self,
+        heap: &mut Heap,
+        last_id: u64,
+    ) -> Result<Vec<Element>, Error> {
+        let entries = match self.source_date_epoch {
+            Some(epoch) => Cow::Owned(Self::normalized(&self.entries, epoch)),
+            None => Cow::Borrowed(&self.entries),
+        };
+
+        let mut blobs = Vec::new();
+        Self::blobs(&entries, &mut HashSet::new(), &mut blobs);
+
+        self.encode_all(&blobs, heap, |encoded| {
+            let mut id = last_id;
+            let mut hardlinks = HashMap::new();
+            entries
+                .iter()
+                .map(|entry| self.entry_element(entry, &mut id, &mut hardlinks, encoded))
+                .collect()
+        })
+    }
+
+    /// Entries sorted by name, with their metadata made independent of
+    /// when and by whom the files were created.
+    fn normalized(entries: &[Entry], epoch: NaiveDateTime) -> Vec<Entry> {
+        let mut entries: Vec<Entry> = entries
+            .iter()
+            .map(|entry| {
+                let mut entry = entry.clone();
+                let metadata = &mut entry.metadata;
+                for time in [
+                    &mut metadata.ctime,
+                    &mut metadata.mtime,
+                    &mut metadata.atime,
+                ] {
+                    *time = time.map(|time| time.min(epoch));
+                }
+                metadata.uid = metadata.uid.map(|_| 0);
+                metadata.gid = metadata.gid.map(|_| 0);
+                metadata.xattrs.sort();
+                if let Kind::Directory(children) = &entry.kind {
+                    entry.kind = Kind::Directory(Self::normalized(children, epoch));
+                }
+                entry
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries
+    }
+
+    /// All data to store, in the order `entry_element` stores it.
+    fn blobs<'b>(
+        entries: &'b [Entry],
+        hardlinks: &mut HashSet<(u64, u64)>,
+        blobs: &mut Vec<Blob<'b>>,
+    ) {
+        for entry in entries {
+            let original = match Self::link_key(entry) {
+                Some(key) => hardlinks.insert(key),
+                None => true,
+            };
+            match &entry.kind {
+                Kind::File(Source::Path(path)) if original => blobs.push(Blob::Path(path)),
+                Kind::File(Source::Buffer(buffer)) if original => blobs.push(Blob::Buffer(buffer)),
+                _ => {}
+            }
+            for (_, value) in &entry.metadata.xattrs {
+                blobs.push(Blob::Buffer(value));
+            }
+            if let Kind::Directory(children) = &entry.kind {
+                Self::blobs(children, hardlinks, blobs);
+            }
+        }
+    }
+
+    /// Compress and checksum all data into the heap, on several threads if
+    /// asked to, and hand the results to `consume` in the same order as the
+    /// data. Only a few blobs are encoded ahead of the one `consume` is
+    /// waiting for, and what doesn't fit in memory is spooled to disk.
+    fn encode_all<T>(
+        &self,
+        blobs: &[Blob],
+        heap: &mut Heap,
+        consume: impl FnOnce(&mut EncodedIter) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        if self.jobs == 1 {
+            return consume(&mut blobs.iter().map(|blob| {
+                let offset = heap.end();
+                let mut encoded = self.encode(blob.open()?, &mut *heap)?;
+                encoded.offset = offset;
+                Ok(encoded)
+            }));
+        }
+
+        let (tasks, queue) = mpsc::channel::<usize>();
+        let queue = &Mutex::new(queue);
+        let (done, results) = mpsc::channel();
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.jobs)
+                .map(|_| {
+                    let done = done.clone();
+                    scope.spawn(move || {
+                        // stops once all tasks are handed out or the results
+                        // are no longer wanted.
+                        loop {
+                            let Ok(index) = queue.lock().unwrap().recv() else {
+                                break;
+                            };
+                            let mut spool = SpooledTempFile::new(SPOOL_SIZE);
+                            let result = blobs[index]
+                                .open()
+                                .and_then(|reader| self.encode(reader, &mut spool))
+                                .map(|encoded| (encoded, spool));
+                            if done.send((index, result)).is_err() {
+                                break;
+                            }
+                        }
+                    })
+                })
+                .collect();
+            drop(done);
+
+            let result = consume(&mut InOrder {
+                tasks,
+                results,
+                heap,
+                early: HashMap::new(),
+                next: 0,
+                sent: 0,
+                total: blobs.len(),
+                window: 2 * self.jobs,
+            });
+
+            for worker in workers {
+                worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            }
+            result
+        })
+    }
+
+    /// Compress and checksum data from `reader` into `writer`. Empty data
+    /// is written as nothing at all, which needs no decoding.
+    pub(crate) fn encode<R: Read, W: Write>(&self, reader: R, writer: W) -> Result<Encoded, Error> {
+        let mut reader = BufReader::new(ChecksumReader {
+            reader,
+            checksum: Some(Checksum::new(&self.checksum_alg)?),
+        });
+        let mut writer = ChecksumWriter {
+            writer,
+            checksum: Checksum::new(&self.checksum_alg)?,
+            written: 0,
+        };
+
+        let size = if reader.fill_buf()?.is_empty() {
+            0
+        } else {
+            self.encoding.encode_to(&mut reader, &mut writer)?
+        };
+        writer.flush()?;
+
+        let extracted = reader.into_inner().checksum.expect("data is checksummed");
+        Ok(Encoded {
+            size,
+            offset: 0,
+            length: writer.written,
+            extracted_checksum: extracted.finish(),
+            archived_checksum: writer.checksum.finish(),
+        })
+    }
+
+    /// Device and inode of files on disk with more than one link.
+    fn link_key(entry: &Entry) -> Option<(u64, u64)> {
+        match (&entry.kind, &entry.metadata) {
+            (
+                Kind::File(_),
+                Metadata {
+                    nlink: Some(nlink),
+                    deviceno: Some(deviceno),
+                    inode: Some(inode),
+                    ..
+                },
+            ) if *nlink > 1 => Some((*deviceno, *inode)),
+            _ => None,
+        }
+    }
+
+    fn creation_time_element(&self) -> Element {
+        let time = self
+            .source_date_epoch
+            .unwrap_or_else(|| Utc::now().naive_utc());
+        text_element(
+            "creation-time",
+            time.format(CREATION_TIME_FORMAT).to_string(),
+        )
+    }
+
+    pub(crate) fn checksum_element(&self, size: usize) -> Element {
+        let mut checksum = Element::new("checksum");
+        checksum
+            .attributes
+            .insert("style".into(), self.checksum_name().into());
+        checksum.children.push(text_element("offset", 0));
+        checksum.children.push(text_element("size", size));
+        checksum
+    }
+
+    fn checksum_name(&self) -> &str {
+        self.checksum_alg.name().unwrap_or("none")
+    }
+
+    /// Files on disk with more than one link are stored as hardlinks: the
+    /// first one found is the original and carries the data, the others
+    /// refer to its id. `hardlinks` maps device and inode to that id.
+    ///
+    /// The data of files and extended attributes is taken from `encoded`.
+    fn entry_element(
+        &self,
+        entry: &Entry,
+        id: &mut u64,
+        hardlinks: &mut HashMap<(u64, u64), u64>,
+        encoded: &mut EncodedIter,
+    ) -> Result<Element, Error> {
+        *id += 1;
+        let mut file = Element::new("file");
+        file.attributes.insert("id".into(), id.to_string());
+
+        let hardlink = Self::link_key(entry).map(|key| match hardlinks.get(&key) {
+            Some(original) => Hardlink::Of(*original),
+            None => {
+                hardlinks.insert(key, *id);
+                Hardlink::Original
+            }
+        });
+
+        // links share the data of their original.
+        if let (Kind::File(_), None | Some(Hardlink::Original)) = (&entry.kind, hardlink) {
+            let data = encoded.next().expect("data of every file is encoded")?;
+            if data.size != 0 {
+                file.children.push(self.data_element(&data));
+            }
+        }
+
+        // extended attributes are stored like data, with a name.
+        for (ea_id, (name, _)) in entry.metadata.xattrs.iter().enumerate() {
+            let data = encoded.next().expect("data of every xattr is encoded")?;
+            let mut ea = self.data_element(&data);
+            ea.name = "ea".into();
+            ea.attributes.insert("id".into(), ea_id.to_string());
+            ea.children.insert(0, text_element("name", name));
+            file.children.push(ea);
+        }
+
+        let metadata = &entry.metadata;
+        let times = [
+            ("ctime", metadata.ctime),
+            ("mtime", metadata.mtime),
+            ("atime", metadata.atime),
+        ];
+        for (name, time) in times.iter() {
+            if let Some(time) = time {
+                file.children
+                    .push(text_element(name, time.format(TIME_FORMAT).to_string()));
+            }
+        }
+        if let Some(gid) = metadata.gid {
+            file.children.push(text_element("gid", gid));
+        }
+        if let Some(uid) = metadata.uid {
+            file.children.push(text_element("uid", uid));
+        }
+        if let Some(mode) = metadata.mode {
+            file.children
+                .push(text_element("mode", format!("{:04o}", mode)));
+        }
+        // these differ between every copy of a file.
+        if self.source_date_epoch.is_none() {
+            if let Some(deviceno) = metadata.deviceno {
+                file.children.push(text_element("deviceno", deviceno));
+            }
+            if let Some(inode) = metadata.inode {
+                file.children.push(text_element("inode", inode));
+            }
+        }
+
+        let ftype = match &entry.kind {
+            Kind::File(_) if hardlink.is_some() => FileType::Hardlink,
+            Kind::File(_) => FileType::File,
+            Kind::Directory(_) => FileType::Directory,
+            Kind::Symlink(target, link_type) => {
+                let mut link = text_element("link", target);
+                link.attributes
+                    .insert("type".into(), link_type.name().into());
+                file.children.push(link);
+                FileType::Symlink
+            }
+            Kind::Special(ftype, device) => {
+                if let Some(device) = device {
+                    let mut element = Element::new("device");
+                    element.children.push(text_element("major", device.major));
+                    element.children.push(text_element("minor", device.minor));
+                    file.children.push(element);
+                }
+                *ftype
+            }
+        };
+        let mut type_element = text_element("type", ftype.name());
+        if let Some(hardlink) = hardlink {
+            type_element
+                .attributes
+                .insert("link".into(), hardlink.name());
+        }
+        file.children.push(type_element);
+        file.children.push(text_element("name", &entry.name));
+
+        if let Kind::Directory(children) = &entry.kind {
+            for child in children {
+                file.children
+                    .push(self.entry_element(child, id, hardlinks, encoded)?);
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// Describe compressed data in the heap.
+    pub(crate) fn data_element(&self, data: &Encoded) -> Element {
+        // empty data is stored as it is.
+        let style = match data.size {
+            0 => Encoding::OctetStream.style(),
+            _ => self.encoding.style(),
+        };
+        let mut encoding = Element::new("encoding");
+        encoding.attributes.insert("style".into(), style.into());
+
+        let mut element = Element::new("data");
+        element.children.push(text_element("length", data.length));
+        element.children.push(text_element("offset", data.offset));
+        element.children.push(text_element("size", data.size));
+        element.children.push(encoding);
+        element
+            .children
+            .push(self.digest_element("extracted-checksum", &data.extracted_checksum));
+        element
+            .children
+            .push(self.digest_element("archived-checksum", &data.archived_checksum));
+
+        element
+    }
+
+    fn digest_element(&self, name: &str, digest: &[u8]) -> Element {
+        let mut element = text_element(name, checksum::to_hex(digest));
+        element
+            .attributes
+            .insert("style".into(), self.checksum_name().into());
+        element
+    }
+}
+
+/// Data encoded on a worker thread, not yet in the heap.
+type Spooled = Result<(Encoded, SpooledTempFile), Error>;
+
+/// Results of encoding on worker threads, put back in order and appended to
+/// the heap. Blobs are only handed out to the workers up to `window` ahead
+/// of the next result.
+struct InOrder<'h> {
+    tasks: mpsc::Sender<usize>,
+    results: mpsc::Receiver<(usize, Spooled)>,
+    heap: &'h mut Heap,
+    /// Results that arrived before their turn.
+    early: HashMap<usize, Spooled>,
+    next: usize,
+    sent: usize,
+    total: usize,
+    window: usize,
+}
+
+impl InOrder<'_> {
+    fn append(&mut self, spooled: Spooled) -> Result<Encoded, Error> {
+        let (mut encoded, mut spool) = spooled?;
+        encoded.offset = self.heap.end();
+        spool.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut spool, self.heap)?;
+        Ok(encoded)
+    }
+}
+
+impl Iterator for InOrder<'_> {
+    type Item = Result<Encoded, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.total {
+            return None;
+        }
+
+        while self.sent < self.total && self.sent < self.next + self.window {
+            // if all workers are gone, that shows when waiting below.
+            let _ = self.tasks.send(self.sent);
+            self.sent += 1;
+        }
+
+        loop {
+            if let Some(spooled) = self.early.remove(&self.next) {
+                self.next += 1;
+                return Some(self.append(spooled));
+            }
+            match self.results.recv() {
+                Ok((index, result)) => {
+                    self.early.insert(index, result);
+                }
+                // a worker panicked, which is raised once it is joined.
+                Err(_) => {
+                    let error = std::io::Error::other("encoding thread panicked");
+                    return Some(Err(error.into()));
+                }
+            }
+        }
+    }
+}
+
+impl Default for ArchiveBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn text_element<T: ToString>(name: &str, text: T) -> Element {
+    let mut element = Element::new(name);
+    element.text = Some(text.to_string());
+    element
+}
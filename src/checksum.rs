@@ -0,0 +1,114 @@
+use crate::header::ChecksumAlg;
+use digest::DynDigest;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Result as IoResult, Write};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Errors {
+    #[error("Unsupported checksum algorithm {0:?}.")]
+    UnsupportedAlg(ChecksumAlg),
+}
+
+/// Incremental checksum over any of the supported algorithms.
+///
+/// Data can be fed in with `update` or by writing to it.
+pub struct Checksum {
+    digest: Box<dyn DynDigest>,
+}
+
+impl Checksum {
+    pub fn new(alg: &ChecksumAlg) -> Result<Checksum, Errors> {
+        let digest: Box<dyn DynDigest> = match alg {
+            ChecksumAlg::SHA1 => Box::new(sha1::Sha1::default()),
+            ChecksumAlg::MD5 => Box::new(md5::Md5::default()),
+            ChecksumAlg::SHA256 => Box::new(sha2::Sha256::default()),
+            ChecksumAlg::SHA512 => Box::new(sha2::Sha512::default()),
+            ChecksumAlg::Other(name) => match ChecksumAlg::from_name(name) {
+                ChecksumAlg::Other(_) | ChecksumAlg::None => {
+                    return Err(Errors::UnsupportedAlg(alg.clone()))
+                }
+                known => return Checksum::new(&known),
+            },
+            other => return Err(Errors::UnsupportedAlg(other.clone())),
+        };
+
+        Ok(Checksum { digest })
+    }
+
+    /// Size of the resulting digest in bytes.
+    pub fn size(&self) -> usize {
+        self.digest.output_size()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.digest.input(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.digest.result().into_vec()
+    }
+}
+
+impl Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// Reader that hashes everything passing through it.
+pub(crate) struct ChecksumReader<R> {
+    pub(crate) reader: R,
+    pub(crate) checksum: Option<Checksum>,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let read = self.reader.read(buf)?;
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+/// Writer that hashes and counts everything passing through it.
+pub(crate) struct ChecksumWriter<W> {
+    pub(crate) writer: W,
+    pub(crate) checksum: Checksum,
+    pub(crate) written: u64,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let written = self.writer.write(buf)?;
+        self.checksum.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.writer.flush()
+    }
+}
+
+/// Compute the digest of data in one go.
+pub fn digest(alg: &ChecksumAlg, data: &[u8]) -> Result<Vec<u8>, Errors> {
+    let mut checksum = Checksum::new(alg)?;
+    checksum.update(data);
+    Ok(checksum.finish())
+}
+
+/// Format a digest as lowercase hex, the way it is stored in the toc.
+pub fn to_hex(digest: &[u8]) -> String {
+    let mut hex = String::with_capacity(digest.len() * 2);
+    for byte in digest {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
@@ -0,0 +1,158 @@
+// `serde_derive` expands to code that newer compilers lint against.
+#![allow(non_local_definitions)]
+
+use crate::Error;
+use libflate::zlib;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Errors {
+    #[error("Unknown encoding ‘{0}’.")]
+    UnknownEncoding(String),
+    #[error("Encoding ‘{0}’ is not supported by this build.")]
+    EncodingDisabled(String),
+}
+
+/// How file data is encoded in the heap, as given by the style attribute of
+/// the `<encoding>` element.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub enum Encoding {
+    #[default]
+    OctetStream,
+    Gzip,
+    Bzip2,
+    Lzma,
+    Xz,
+    Other(String),
+}
+
+impl Encoding {
+    /// All encodings this build can read and write.
+    pub fn supported() -> Vec<Encoding> {
+        let mut supported = vec![Encoding::OctetStream, Encoding::Gzip];
+        if cfg!(feature = "bzip2") {
+            supported.push(Encoding::Bzip2);
+        }
+        if cfg!(feature = "xz") {
+            supported.push(Encoding::Lzma);
+            supported.push(Encoding::Xz);
+        }
+        supported
+    }
+
+    /// Parse the style attribute of an `<encoding>` element.
+    pub fn from_style(style: &str) -> Encoding {
+        match style {
+            "application/octet-stream" => Encoding::OctetStream,
+            "application/x-gzip" => Encoding::Gzip,
+            "application/x-bzip2" => Encoding::Bzip2,
+            "application/x-lzma" => Encoding::Lzma,
+            "application/x-xz" => Encoding::Xz,
+            other => Encoding::Other(other.into()),
+        }
+    }
+
+    /// Style attribute of the `<encoding>` element.
+    pub fn style(&self) -> &str {
+        match self {
+            Encoding::OctetStream => "application/octet-stream",
+            Encoding::Gzip => "application/x-gzip",
+            Encoding::Bzip2 => "application/x-bzip2",
+            Encoding::Lzma => "application/x-lzma",
+            Encoding::Xz => "application/x-xz",
+            Encoding::Other(style) => style,
+        }
+    }
+
+    /// Parse a short name as used on the command line.
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "none" => Some(Encoding::OctetStream),
+            "gzip" => Some(Encoding::Gzip),
+            "bzip2" => Some(Encoding::Bzip2),
+            "lzma" => Some(Encoding::Lzma),
+            "xz" => Some(Encoding::Xz),
+            _ => None,
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        Self::supported().contains(self)
+    }
+
+    /// Wrap a reader of encoded data into one that yields the decoded data.
+    pub fn decoder<'a, R: Read + 'a>(&self, reader: R) -> Result<Box<dyn Read + 'a>, Error> {
+        match self {
+            Encoding::OctetStream => Ok(Box::new(reader)),
+            Encoding::Gzip => Ok(Box::new(zlib::Decoder::new(reader)?)),
+            #[cfg(feature = "bzip2")]
+            Encoding::Bzip2 => Ok(Box::new(bzip2::read::BzDecoder::new(reader))),
+            #[cfg(feature = "xz")]
+            Encoding::Lzma => {
+                let stream = xz2::stream::Stream::new_lzma_decoder(u64::MAX)
+                    .map_err(std::io::Error::from)?;
+                Ok(Box::new(xz2::read::XzDecoder::new_stream(reader, stream)))
+            }
+            #[cfg(feature = "xz")]
+            Encoding::Xz => Ok(Box::new(xz2::read::XzDecoder::new(reader))),
+            other => Err(other.unsupported().into()),
+        }
+    }
+
+    /// Encode data in one go. Compression levels are fixed, so the same data
+    /// always encodes to the same bytes.
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut encoded = Vec::new();
+        self.encode_to(data, &mut encoded)?;
+        Ok(encoded)
+    }
+
+    /// Encode everything `reader` yields into `writer`, returning the number
+    /// of bytes read.
+    pub fn encode_to<R: Read, W: Write>(&self, mut reader: R, mut writer: W) -> Result<u64, Error> {
+        match self {
+            Encoding::OctetStream => Ok(std::io::copy(&mut reader, &mut writer)?),
+            Encoding::Gzip => {
+                let mut encoder = zlib::Encoder::new(writer)?;
+                let size = std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish().into_result()?;
+                Ok(size)
+            }
+            #[cfg(feature = "bzip2")]
+            Encoding::Bzip2 => {
+                let mut encoder = bzip2::write::BzEncoder::new(writer, bzip2::Compression::Default);
+                let size = std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?;
+                Ok(size)
+            }
+            #[cfg(feature = "xz")]
+            Encoding::Lzma => {
+                let options =
+                    xz2::stream::LzmaOptions::new_preset(6).map_err(std::io::Error::from)?;
+                let stream = xz2::stream::Stream::new_lzma_encoder(&options)
+                    .map_err(std::io::Error::from)?;
+                let mut encoder = xz2::write::XzEncoder::new_stream(writer, stream);
+                let size = std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?;
+                Ok(size)
+            }
+            #[cfg(feature = "xz")]
+            Encoding::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(writer, 6);
+                let size = std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?;
+                Ok(size)
+            }
+            other => Err(other.unsupported().into()),
+        }
+    }
+
+    fn unsupported(&self) -> Errors {
+        match self {
+            Encoding::Other(style) => Errors::UnknownEncoding(style.clone()),
+            other => Errors::EncodingDisabled(other.style().into()),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use crate::{archive, builder, checksum, encoding, extract, header, limits, rebuild, toc};
+use std::io;
+use thiserror::Error;
+
+/// Any error this crate can return. The error of the module where it
+/// happened is kept as the source.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+    #[error("Cannot decompress toc")]
+    Zlib(#[source] io::Error),
+    #[error("Cannot parse toc")]
+    Xml(#[from] xmltree::ParseError),
+    #[error("Cannot write toc")]
+    XmlWrite(#[from] xmltree::Error),
+    #[error("Invalid header")]
+    Header(#[from] header::Error),
+    #[error("Invalid toc")]
+    Toc(#[from] toc::Errors),
+    #[error("Checksum error")]
+    Checksum(#[from] checksum::Errors),
+    #[error("Encoding error")]
+    Encoding(#[from] encoding::Errors),
+    #[error("Cannot read archive")]
+    Archive(#[from] archive::Errors),
+    #[error("Cannot extract archive")]
+    Extract(#[from] extract::Errors),
+    #[error("Cannot build archive")]
+    Builder(#[from] builder::Errors),
+    #[error("Limit exceeded")]
+    Limits(#[from] limits::Errors),
+    #[error("Cannot rebuild archive")]
+    Rebuild(#[from] rebuild::Errors),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Format an error followed by all of its sources, separated by colons.
+pub fn describe(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message = format!("{}: {}", message, cause);
+        source = cause.source();
+    }
+    message
+}
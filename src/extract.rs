@@ -0,0 +1,858 @@
+use crate::archive::HeapReader;
+use crate::limits::{self, ReadLimits};
+use crate::toc::{Device, File, FileData, FileEntry, FileType, Files, Hardlink, LinkType};
+use crate::xattr;
+use crate::Archive;
+use crate::Error;
+use chrono::{DateTime, Utc};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Errors {
+    #[error("Entry in ‘{0}’ has no name.")]
+    NoName(String),
+    #[error("Entry ‘{0}’ has no type.")]
+    NoType(String),
+    #[error("Symlink ‘{0}’ has no target.")]
+    NoLinkTarget(String),
+    #[error("Device ‘{0}’ has no device number.")]
+    NoDevice(String),
+    #[error("Refusing to extract ‘{0}’, unsafe name.")]
+    UnsafePath(String),
+    #[error("Refusing to extract ‘{0}’, path leads through a symlink.")]
+    SymlinkEscape(String),
+    #[error("Unsupported encoding ‘{0}’ for ‘{1}’.")]
+    UnsupportedEncoding(String, String),
+    #[error("Data of ‘{0}’ overlaps data before it, cannot extract in a single pass.")]
+    DataOutOfOrder(String),
+    #[error("Not found in archive: ‘{}’.", .0.join("’, ‘"))]
+    NotFound(Vec<String>),
+}
+
+/// Opens a region of the heap of a seekable archive.
+type OpenHeap<R> = for<'b> fn(&'b Archive<R>, u64, u64) -> Result<HeapReader<'b, R>, Error>;
+
+/// Opens another reader of the whole archive, for a worker thread.
+type Reopen<'a> = Box<dyn Fn() -> std::io::Result<Box<dyn ReadSeek + 'a>> + Sync + 'a>;
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Data in the heap that is written once all entries have been created.
+struct Pending {
+    data: FileData,
+    /// Path in the archive.
+    path: PathBuf,
+    target: PathBuf,
+    /// Name of the extended attribute, or `None` for the file data.
+    xattr: Option<String>,
+}
+
+/// Extracts the entries of an archive into a destination directory.
+pub struct Extractor<'a, R: Read> {
+    archive: &'a Archive<R>,
+    dest: PathBuf,
+    paths: Vec<PathBuf>,
+    /// Which of `paths` matched at least one entry.
+    matched: RefCell<Vec<bool>>,
+    /// Where the first file of each group of hardlinks (keyed by the id of
+    /// the original) was extracted to.
+    hardlinks: RefCell<HashMap<u64, PathBuf>>,
+    xattrs: bool,
+    preserve_permissions: bool,
+    same_owner: bool,
+    numeric_owner: bool,
+    unsafe_paths: bool,
+    umask: u32,
+    jobs: usize,
+    /// Bytes of data extracted so far.
+    extracted: AtomicU64,
+    /// How to read data, `None` when extracting in a single pass.
+    open: Option<OpenHeap<R>>,
+    reopen: Option<Reopen<'a>>,
+    /// Data to write and metadata to apply, in this order, once all entries
+    /// have been created.
+    pending: RefCell<Vec<Pending>>,
+    deferred: RefCell<Vec<(FileEntry, PathBuf)>>,
+}
+
+impl<'a, R: Read> Extractor<'a, R> {
+    /// Create an extractor writing the files of `archive` to `dest`.
+    pub fn new(archive: &'a Archive<R>, dest: &Path) -> Self {
+        Extractor {
+            archive,
+            dest: dest.to_path_buf(),
+            paths: Vec::new(),
+            matched: RefCell::new(Vec::new()),
+            hardlinks: RefCell::new(HashMap::new()),
+            xattrs: true,
+            preserve_permissions: false,
+            same_owner: false,
+            numeric_owner: false,
+            unsafe_paths: false,
+            umask: 0o022,
+            jobs: 1,
+            extracted: AtomicU64::new(0),
+            open: None,
+            reopen: None,
+            pending: RefCell::new(Vec::new()),
+            deferred: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Only extract the given paths (and everything below them).
+    pub fn paths<P: AsRef<Path>>(mut self, paths: &[P]) -> Self {
+        self.paths = paths.iter().map(|p| p.as_ref().to_path_buf()).collect();
+        self.matched = RefCell::new(vec![false; self.paths.len()]);
+        self
+    }
+
+    /// Whether to apply extended attributes, on by default.
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Apply the modes from the archive as they are. Otherwise, like tar,
+    /// only the permission bits are kept and the umask is applied.
+    pub fn preserve_permissions(mut self, preserve: bool) -> Self {
+        self.preserve_permissions = preserve;
+        self
+    }
+
+    /// Give files the owner and group from the archive, looked up by name
+    /// and falling back to the numeric ids.
+    pub fn same_owner(mut self, same_owner: bool) -> Self {
+        self.same_owner = same_owner;
+        self
+    }
+
+    /// Only use the numeric ids from the archive when setting owners.
+    pub fn numeric_owner(mut self, numeric_owner: bool) -> Self {
+        self.numeric_owner = numeric_owner;
+        self
+    }
+
+    /// Extract names containing `..` or slashes and write through symlinks.
+    /// Only use this on archives you trust.
+    pub fn unsafe_paths(mut self, unsafe_paths: bool) -> Self {
+        self.unsafe_paths = unsafe_paths;
+        self
+    }
+
+    /// Read and decode data on this many threads. Entries are still created
+    /// in order and metadata is applied last, so the result is the same.
+    ///
+    /// Each thread needs its own view of the archive: memory mapped archives
+    /// are sliced, others have to be opened again with `reopen_with`.
+    /// Otherwise, and when extracting in a single pass, all data is written
+    /// on the calling thread.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// How worker threads open their own reader of the archive, such as by
+    /// opening its file again.
+    pub fn reopen_with<F, T>(mut self, reopen: F) -> Self
+    where
+        F: Fn() -> std::io::Result<T> + Sync + 'a,
+        T: Read + Seek + 'a,
+    {
+        self.reopen = Some(Box::new(move || {
+            Ok(Box::new(reopen()?) as Box<dyn ReadSeek + 'a>)
+        }));
+        self
+    }
+
+    /// Run the extraction, reading the heap front to back exactly once so
+    /// that the archive can come from a pipe. Data shared by several entries
+    /// is buffered, other overlapping data is an error.
+    ///
+    /// The reader of the archive has to be at the start of the heap, as it
+    /// is right after the toc has been read.
+    pub fn extract_stream(mut self) -> Result<(), Error> {
+        self.open = None;
+        self.run()
+    }
+
+    /// Create all entries in toc order, then write their data in the order
+    /// it appears in the heap, then apply their metadata.
+    fn run(mut self) -> Result<(), Error> {
+        self.umask = umask();
+        fs::create_dir_all(&self.dest)?;
+        let files = self.archive.toc().files()?;
+        self.extract_files(&files)?;
+        self.write_pending()?;
+
+        for (entry, target) in self.deferred.take() {
+            self.apply_metadata(&entry, &target)?;
+        }
+
+        let missing: Vec<String> = self
+            .paths
+            .iter()
+            .zip(self.matched.take())
+            .filter(|(_, matched)| !matched)
+            .map(|(path, _)| path.display().to_string())
+            .collect();
+        if !missing.is_empty() {
+            return Err(Errors::NotFound(missing).into());
+        }
+
+        Ok(())
+    }
+
+    fn extract_files(&self, files: &Files) -> Result<(), Error> {
+        for file in files.iter() {
+            let entry = file.entry()?;
+            let name = entry
+                .name
+                .as_ref()
+                .ok_or_else(|| Errors::NoName(file.path.display().to_string()))?;
+            if !self.unsafe_paths && !safe_name(name) {
+                return Err(Errors::UnsafePath(file.path.join(name).display().to_string()).into());
+            }
+            let path = file.path.join(name);
+
+            if !self.selected(&path) {
+                continue;
+            }
+
+            self.extract_file(&file, &entry, &path)?;
+            self.extract_files(&file.files())?;
+
+            // only after all data is written, so that directories stay
+            // writable and their times are not changed by their children.
+            let target = self.dest.join(&path);
+            self.deferred.borrow_mut().push((entry, target));
+        }
+
+        Ok(())
+    }
+
+    /// Decides if a path should be extracted. Parents of selected paths are
+    /// selected as well, so that the directories leading up to them exist,
+    /// but only the path itself and what is below it count as a match.
+    fn selected(&self, path: &Path) -> bool {
+        let mut matched = self.matched.borrow_mut();
+        let mut selected = self.paths.is_empty();
+        for (p, matched) in self.paths.iter().zip(matched.iter_mut()) {
+            if path.starts_with(p) {
+                *matched = true;
+                selected = true;
+            } else if p.starts_with(path) {
+                selected = true;
+            }
+        }
+        selected
+    }
+
+    fn extract_file(&self, file: &File, entry: &FileEntry, path: &Path) -> Result<(), Error> {
+        if !self.unsafe_paths {
+            self.check_symlinks(path)?;
+        }
+
+        let target = self.dest.join(path);
+        let ftype = entry
+            .ftype
+            .ok_or_else(|| Errors::NoType(path.display().to_string()))?;
+
+        match (ftype, entry.hardlink) {
+            (FileType::Directory, _) => fs::create_dir_all(&target)?,
+            (FileType::Hardlink, hardlink) => {
+                let group = match hardlink {
+                    Some(Hardlink::Of(id)) => Some(id),
+                    _ => entry.id,
+                };
+                let existing = group.and_then(|id| self.hardlinks.borrow().get(&id).cloned());
+
+                // whichever file of the group comes first gets the data, no
+                // matter if it is the original or a link.
+                match existing {
+                    Some(existing) => fs::hard_link(existing, &target)?,
+                    None => {
+                        self.write_data(&file.data()?, &target, path)?;
+                        if let Some(id) = group {
+                            self.hardlinks.borrow_mut().insert(id, target.clone());
+                        }
+                    }
+                }
+            }
+            (FileType::File, _) => self.write_data(&entry.data, &target, path)?,
+            (FileType::Symlink, _) => {
+                let link = entry
+                    .link
+                    .as_ref()
+                    .ok_or_else(|| Errors::NoLinkTarget(path.display().to_string()))?;
+                symlink(link, entry.link_type, &target)?;
+            }
+            (FileType::Fifo, _) => mkfifo(&target, entry.mode.unwrap_or(0o644))?,
+            (FileType::CharacterSpecial, _) | (FileType::BlockSpecial, _) => {
+                let device = entry
+                    .device
+                    .ok_or_else(|| Errors::NoDevice(path.display().to_string()))?;
+                let mode = entry.mode.unwrap_or(0o644);
+                match mknod(&target, ftype, mode, device) {
+                    // only root may create devices, like tar we carry on.
+                    Err(ref e) if e.kind() == std::io::ErrorKind::PermissionDenied => {}
+                    result => result?,
+                }
+            }
+            // sockets can't be recreated and whiteouts only make sense in
+            // union mounts.
+            (FileType::Socket, _) | (FileType::Whiteout, _) => {}
+        }
+
+        if self.xattrs && ftype != FileType::Symlink {
+            self.apply_xattrs(entry, &target)?;
+        }
+
+        Ok(())
+    }
+
+    /// Make sure neither the path nor any of its parents inside the
+    /// destination is a symlink, as writing there could end up anywhere.
+    fn check_symlinks(&self, path: &Path) -> Result<(), Errors> {
+        for ancestor in path.ancestors() {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+
+            let is_symlink = fs::symlink_metadata(self.dest.join(ancestor))
+                .map(|metadata| metadata.file_type().is_symlink())
+                .unwrap_or(false);
+            if is_symlink {
+                return Err(Errors::SymlinkEscape(path.display().to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set owner, permissions and timestamps of an extracted file.
+    fn apply_metadata(&self, entry: &FileEntry, target: &Path) -> Result<(), Error> {
+        // sockets, whiteouts and devices we can't create are skipped.
+        if fs::symlink_metadata(target).is_err() {
+            return Ok(());
+        }
+
+        if self.same_owner {
+            let (uid, gid) = if self.numeric_owner {
+                (entry.uid, entry.gid)
+            } else {
+                (
+                    entry.user.as_deref().and_then(user_id).or(entry.uid),
+                    entry.group.as_deref().and_then(group_id).or(entry.gid),
+                )
+            };
+            lchown(target, uid, gid)?;
+        }
+
+        // changing the owner clears the setuid bits, so this comes after.
+        if let (Some(mode), false) = (entry.mode, entry.ftype == Some(FileType::Symlink)) {
+            let mode = if self.preserve_permissions {
+                mode
+            } else {
+                mode & 0o777 & !self.umask
+            };
+            set_mode(target, mode)?;
+        }
+
+        if entry.mtime.is_some() || entry.atime.is_some() {
+            set_times(target, entry.atime, entry.mtime)?;
+        }
+
+        Ok(())
+    }
+
+    /// Queue the extended attributes of an extracted file, they are set
+    /// along with the file data.
+    fn apply_xattrs(&self, entry: &FileEntry, target: &Path) -> Result<(), Error> {
+        if entry.xattrs.is_empty() || fs::symlink_metadata(target).is_err() {
+            return Ok(());
+        }
+
+        let mut pending = self.pending.borrow_mut();
+        for xattr in &entry.xattrs {
+            pending.push(Pending {
+                data: xattr.data.clone(),
+                path: target.to_path_buf(),
+                target: target.to_path_buf(),
+                xattr: Some(xattr.name.clone()),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Create an empty file, its data is written later.
+    fn write_data(&self, data: &Option<FileData>, target: &Path, path: &Path) -> Result<(), Error> {
+        fs::File::create(target)?;
+        if let Some(data) = data {
+            self.pending.borrow_mut().push(Pending {
+                data: data.clone(),
+                path: path.to_path_buf(),
+                target: target.to_path_buf(),
+                xattr: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Write the pending data in heap order, spread over worker threads
+    /// if there are several jobs and a way for each of them to read.
+    fn write_pending(&self) -> Result<(), Error> {
+        let mut pending = self.pending.take();
+        pending.sort_by_key(|pending| pending.data.offset);
+        let limits = self.archive.limits();
+        let extracted = &self.extracted;
+
+        let heap = self.archive.mapped_heap();
+        let reopen = self.reopen.as_deref();
+        if self.jobs == 1 || self.open.is_none() || (heap.is_none() && reopen.is_none()) {
+            return self.read_pending(&pending, &mut |pending, raw| {
+                write_pending(limits, extracted, pending, raw)
+            });
+        }
+
+        let heap_offset = self.archive.heap_offset();
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let work = || -> Result<(), Error> {
+            let mut reader = match heap {
+                Some(_) => None,
+                None => reopen.map(|reopen| reopen()).transpose()?,
+            };
+            while !failed.load(Ordering::Relaxed) {
+                let Some(pending) = pending.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                    break;
+                };
+                let data = &pending.data;
+                match (&mut reader, heap) {
+                    (Some(reader), _) => {
+                        reader.seek(SeekFrom::Start(heap_offset + data.offset))?;
+                        write_exact(limits, extracted, pending, reader)?;
+                    }
+                    (None, Some(heap)) => {
+                        let raw = data
+                            .offset
+                            .checked_add(data.length)
+                            .and_then(|end| heap.get(data.offset as usize..end as usize))
+                            .ok_or(std::io::ErrorKind::UnexpectedEof)
+                            .map_err(std::io::Error::from)?;
+                        write_pending(limits, extracted, pending, raw)?;
+                    }
+                    (None, None) => unreachable!(),
+                }
+            }
+            Ok(())
+        };
+
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.jobs)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let result = work();
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        result
+                    })
+                })
+                .collect();
+            workers.into_iter().try_for_each(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+        })
+    }
+
+    /// Read the raw data of each pending entry, seeking to it or reading the
+    /// heap once as it passes by.
+    fn read_pending<'p>(
+        &self,
+        pending: &'p [Pending],
+        write: &mut dyn FnMut(&'p Pending, &mut dyn Read) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if let Some(open) = self.open {
+            for pending in pending {
+                let mut raw = open(self.archive, pending.data.offset, pending.data.length)?;
+                write(pending, &mut raw)?;
+            }
+            return Ok(());
+        }
+
+        let mut reader = self.archive.reader()?;
+        let mut position = 0;
+        for group in pending.chunk_by(|a, b| a.data.offset == b.data.offset) {
+            let offset = group[0].data.offset;
+            if offset < position {
+                let path = group[0].path.display().to_string();
+                return Err(Errors::DataOutOfOrder(path).into());
+            }
+            skip(&mut *reader, offset - position)?;
+
+            let length = group.iter().map(|p| p.data.length).max().unwrap_or(0);
+            let mut raw = (&mut *reader).take(length);
+            if let [pending] = group {
+                write(pending, &mut raw)?;
+                drain(raw)?;
+            } else {
+                let mut buffer = Vec::new();
+                raw.read_to_end(&mut buffer)?;
+                if buffer.len() as u64 != length {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+                for pending in group {
+                    write(pending, &mut &buffer[..pending.data.length as usize])?;
+                }
+            }
+
+            position = offset + length;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, R: Read + Seek> Extractor<'a, R> {
+    /// Run the extraction.
+    pub fn extract(mut self) -> Result<(), Error> {
+        self.open = Some(Archive::open_heap);
+        self.run()
+    }
+}
+
+/// Decode the raw data of an entry into its file or extended attribute.
+fn write_pending<I: Read>(
+    limits: &ReadLimits,
+    extracted: &AtomicU64,
+    pending: &Pending,
+    raw: I,
+) -> Result<(), Error> {
+    match &pending.xattr {
+        Some(name) => {
+            let mut value = Vec::new();
+            copy_data(
+                limits,
+                extracted,
+                &pending.data,
+                &pending.path,
+                raw,
+                &mut value,
+            )?;
+            set_xattr(&pending.target, name, &value)?;
+        }
+        None => {
+            let mut output = fs::OpenOptions::new().write(true).open(&pending.target)?;
+            copy_data(
+                limits,
+                extracted,
+                &pending.data,
+                &pending.path,
+                raw,
+                &mut output,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Decode an entry from `reader`, which is at the start of its raw data,
+/// making sure all of the raw data is there.
+fn write_exact<I: Read>(
+    limits: &ReadLimits,
+    extracted: &AtomicU64,
+    pending: &Pending,
+    reader: &mut I,
+) -> Result<(), Error> {
+    let mut raw = reader.take(pending.data.length);
+    write_pending(limits, extracted, pending, &mut raw)?;
+    drain(raw)
+}
+
+/// Read the rest of the raw data of an entry, as decoders can stop before
+/// the end of their input.
+fn drain<I: Read>(mut raw: std::io::Take<I>) -> Result<(), Error> {
+    std::io::copy(&mut raw, &mut std::io::sink())?;
+    if raw.limit() != 0 {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// Decode the raw data into output, failing as soon as it grows beyond the
+/// limits.
+fn copy_data<I: Read, W: Write>(
+    limits: &ReadLimits,
+    extracted: &AtomicU64,
+    data: &FileData,
+    path: &Path,
+    raw: I,
+    output: &mut W,
+) -> Result<(), Error> {
+    let name = || path.display().to_string();
+    if data.size > limits.max_file_size {
+        return Err(limits::Errors::FileTooLarge(name(), limits.max_file_size).into());
+    }
+
+    let file_limit = limits.file_limit(data.length);
+    let total_left = limits
+        .max_total_size
+        .saturating_sub(extracted.load(Ordering::Relaxed));
+    let input = decoder(data, path, raw)?;
+    let mut input = input.take(file_limit.min(total_left).saturating_add(1));
+    let copied = std::io::copy(&mut input, output)?;
+
+    if copied > limits.max_file_size {
+        return Err(limits::Errors::FileTooLarge(name(), limits.max_file_size).into());
+    }
+    if copied > file_limit {
+        return Err(limits::Errors::RatioTooHigh(name(), limits.max_ratio).into());
+    }
+    // other threads may have extracted data in the meantime.
+    let total = extracted.fetch_add(copied, Ordering::Relaxed) + copied;
+    if copied > total_left || total > limits.max_total_size {
+        return Err(limits::Errors::TotalTooLarge(limits.max_total_size).into());
+    }
+
+    Ok(())
+}
+
+/// Returns a reader yielding the decoded data of a file.
+fn decoder<'b, I: Read + 'b>(
+    data: &FileData,
+    path: &Path,
+    raw: I,
+) -> Result<Box<dyn Read + 'b>, Error> {
+    if !data.encoding.is_supported() {
+        return Err(Errors::UnsupportedEncoding(
+            data.encoding.style().into(),
+            path.display().to_string(),
+        )
+        .into());
+    }
+
+    data.encoding.decoder(raw)
+}
+
+/// Set an extended attribute, skipping ones the destination does not
+/// support (such as macOS ones on Linux) and ones we may not set.
+fn set_xattr(target: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+    match xattr::set(target, name, value) {
+        Err(ref e) if xattr::is_unsupported(e) || xattr::is_privileged(e, name) => Ok(()),
+        result => result,
+    }
+}
+
+/// Read and throw away `length` bytes.
+fn skip<I: Read>(reader: &mut I, length: u64) -> std::io::Result<()> {
+    let skipped = std::io::copy(&mut reader.take(length), &mut std::io::sink())?;
+    if skipped != length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Whether a name is a single, normal path component.
+fn safe_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(component)), None) => {
+            component == name && !name.contains(&['/', '\\', '\0'][..])
+        }
+        _ => false,
+    }
+}
+
+#[cfg(unix)]
+fn symlink(link: &str, _link_type: Option<LinkType>, target: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(link, target)
+}
+
+#[cfg(windows)]
+fn symlink(link: &str, link_type: Option<LinkType>, target: &Path) -> std::io::Result<()> {
+    match link_type {
+        Some(LinkType::Directory) => std::os::windows::fs::symlink_dir(link, target),
+        _ => std::os::windows::fs::symlink_file(link, target),
+    }
+}
+
+#[cfg(unix)]
+fn mkfifo(target: &Path, mode: u32) -> std::io::Result<()> {
+    let path = crate::xattr::c_path(target)?;
+    match unsafe { libc::mkfifo(path.as_ptr(), mode as libc::mode_t) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(unix)]
+fn mknod(target: &Path, ftype: FileType, mode: u32, device: Device) -> std::io::Result<()> {
+    let path = crate::xattr::c_path(target)?;
+    let kind = match ftype {
+        FileType::BlockSpecial => libc::S_IFBLK,
+        _ => libc::S_IFCHR,
+    };
+    let dev = libc::makedev(device.major as _, device.minor as _);
+    match unsafe { libc::mknod(path.as_ptr(), kind | mode as libc::mode_t, dev) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(unix)]
+fn umask() -> u32 {
+    // there is no way to read the umask without setting it.
+    let umask = unsafe { libc::umask(0o022) };
+    unsafe { libc::umask(umask) };
+    umask as u32
+}
+
+#[cfg(unix)]
+fn set_mode(target: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(target, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(unix)]
+fn lchown(target: &Path, uid: Option<u32>, gid: Option<u32>) -> std::io::Result<()> {
+    let path = crate::xattr::c_path(target)?;
+    // -1 leaves the id unchanged.
+    let uid = uid.map(|uid| uid as libc::uid_t).unwrap_or(!0);
+    let gid = gid.map(|gid| gid as libc::gid_t).unwrap_or(!0);
+    match unsafe { libc::lchown(path.as_ptr(), uid, gid) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Set access and modification time, of the link itself for symlinks.
+#[cfg(unix)]
+fn set_times(
+    target: &Path,
+    atime: Option<DateTime<Utc>>,
+    mtime: Option<DateTime<Utc>>,
+) -> std::io::Result<()> {
+    let path = crate::xattr::c_path(target)?;
+    let timespec = |time: Option<DateTime<Utc>>| match time {
+        Some(time) => libc::timespec {
+            tv_sec: time.timestamp() as libc::time_t,
+            tv_nsec: time.timestamp_subsec_nanos() as _,
+        },
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+    };
+    let times = [timespec(atime), timespec(mtime)];
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(unix)]
+fn user_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if result.is_null() {
+        None
+    } else {
+        Some(passwd.pw_uid as u32)
+    }
+}
+
+#[cfg(unix)]
+fn group_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if result.is_null() {
+        None
+    } else {
+        Some(group.gr_gid as u32)
+    }
+}
+
+#[cfg(not(unix))]
+fn umask() -> u32 {
+    0
+}
+
+#[cfg(not(unix))]
+fn set_mode(_target: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn lchown(_target: &Path, _uid: Option<u32>, _gid: Option<u32>) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_times(
+    _target: &Path,
+    _atime: Option<DateTime<Utc>>,
+    _mtime: Option<DateTime<Utc>>,
+) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn user_id(_name: &str) -> Option<u32> {
+    None
+}
+
+#[cfg(not(unix))]
+fn group_id(_name: &str) -> Option<u32> {
+    None
+}
+
+#[cfg(not(unix))]
+fn mkfifo(_target: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn mknod(_target: &Path, _ftype: FileType, _mode: u32, _device: Device) -> std::io::Result<()> {
+    Ok(())
+}
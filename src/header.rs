@@ -1,19 +1,29 @@
-use byteorder::{BigEndian, ReadBytesExt};
-use failure::Fail;
+// `serde_derive` expands to code that newer compilers lint against.
+#![allow(non_local_definitions)]
+
+use crate::limits::{self, ReadLimits};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use serde::{Deserialize, Serialize};
 use std::fmt;
-use std::io::Read;
+use std::io::{Read, Write};
+use thiserror::Error;
 
 /// Minimal size of header.
 const HEADER_SIZE: usize = 28;
 
-#[derive(Fail, Debug, PartialEq)]
+/// Magic number every archive starts with, 'xar!'.
+const MAGIC: u32 = 0x78617221;
+
+/// Size of the checksum name stored in the extra data for `ChecksumAlg::Other`.
+const CHECKSUM_NAME_SIZE: usize = 36;
+
+#[derive(Error, Debug, PartialEq)]
 pub enum Error {
-    #[fail(display = "Wrong magic number.")]
+    #[error("Wrong magic number.")]
     MagicError,
-    #[fail(display = "Wrong version: {}, expected 1.", _0)]
+    #[error("Wrong version: {0}, expected 1.")]
     Version(u16),
-    #[fail(display = "Header too small: {} bytes, expected 28.", _0)]
+    #[error("Header too small: {0} bytes, expected 28.")]
     HeaderTooSmall(u16),
 }
 
@@ -41,6 +51,47 @@ impl From<u32> for ChecksumAlg {
     }
 }
 
+impl From<&ChecksumAlg> for u32 {
+    fn from(alg: &ChecksumAlg) -> u32 {
+        match alg {
+            ChecksumAlg::None => 0,
+            ChecksumAlg::SHA1 => 1,
+            ChecksumAlg::MD5 => 2,
+            ChecksumAlg::SHA256 => 3,
+            ChecksumAlg::SHA512 => 4,
+            ChecksumAlg::Other(_) => 3,
+            ChecksumAlg::Unknown(i) => *i,
+        }
+    }
+}
+
+impl ChecksumAlg {
+    /// Name of the algorithm as used in the style attribute of checksums in
+    /// the toc.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            ChecksumAlg::SHA1 => Some("sha1"),
+            ChecksumAlg::MD5 => Some("md5"),
+            ChecksumAlg::SHA256 => Some("sha256"),
+            ChecksumAlg::SHA512 => Some("sha512"),
+            ChecksumAlg::Other(name) => Some(name),
+            ChecksumAlg::None | ChecksumAlg::Unknown(_) => None,
+        }
+    }
+
+    /// Parse the style attribute of a checksum in the toc.
+    pub fn from_name(name: &str) -> ChecksumAlg {
+        match name {
+            "none" => ChecksumAlg::None,
+            "sha1" => ChecksumAlg::SHA1,
+            "md5" => ChecksumAlg::MD5,
+            "sha256" => ChecksumAlg::SHA256,
+            "sha512" => ChecksumAlg::SHA512,
+            other => ChecksumAlg::Other(other.into()),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
     pub magic: u32,
@@ -53,9 +104,36 @@ pub struct Header {
 }
 
 impl Header {
+    /// Create a header for a toc of the given lengths.
+    pub fn new(
+        toc_length_compressed: u64,
+        toc_length_uncompressed: u64,
+        checksum_alg: ChecksumAlg,
+    ) -> Header {
+        // other checksums are named in the extra data, padded with zeroes.
+        let data = match &checksum_alg {
+            ChecksumAlg::Other(name) => {
+                let mut data = name.as_bytes().to_vec();
+                data.resize(CHECKSUM_NAME_SIZE, 0);
+                data
+            }
+            _ => Vec::new(),
+        };
+
+        Header {
+            magic: MAGIC,
+            size: (HEADER_SIZE + data.len()) as u16,
+            version: 1,
+            toc_length_compressed,
+            toc_length_uncompressed,
+            checksum_alg,
+            data,
+        }
+    }
+
     pub fn check(&self) -> Result<(), Error> {
         // needs to start with magic sequence 'xar!'.
-        if self.magic != 0x78617221 {
+        if self.magic != MAGIC {
             return Err(Error::MagicError);
         }
 
@@ -76,6 +154,23 @@ impl Header {
         serde_json::to_string(self)
     }
 
+    /// Read a header, refusing ones with more extra data than the limits
+    /// allow before allocating it.
+    pub fn from_read_with_limits<T: Read>(
+        reader: &mut T,
+        limits: &ReadLimits,
+    ) -> Result<Header, crate::Error> {
+        let mut fixed = [0; HEADER_SIZE];
+        reader.read_exact(&mut fixed)?;
+
+        let size = (&fixed[4..6]).read_u16::<BigEndian>()? as u64;
+        if size > limits.max_header_size {
+            return Err(limits::Errors::HeaderTooLarge(size, limits.max_header_size).into());
+        }
+
+        Ok(Header::from_read(&mut (&fixed[..]).chain(reader))?)
+    }
+
     pub fn from_read<T: Read>(reader: &mut T) -> Result<Header, std::io::Error> {
         let magic = reader.read_u32::<BigEndian>()?;
         let size = reader.read_u16::<BigEndian>()?;
@@ -86,11 +181,18 @@ impl Header {
 
         // Read extra data until we've read in the whole header.
         let data_size = (size as usize).saturating_sub(HEADER_SIZE);
-        let mut data = Vec::with_capacity(data_size);
-        data.resize(data_size, 0);
+        let mut data = vec![0; data_size];
         reader.read_exact(&mut data)?;
 
-        let checksum_alg = checksum_alg.into();
+        // with extra data, checksum type 3 is named there rather than
+        // implying sha256.
+        let checksum_alg = match checksum_alg {
+            3 if !data.is_empty() => {
+                let name = data.split(|b| *b == 0).next().unwrap_or(&[]);
+                ChecksumAlg::Other(String::from_utf8_lossy(name).into_owned())
+            }
+            alg => alg.into(),
+        };
 
         Ok(Header {
             magic,
@@ -102,9 +204,22 @@ impl Header {
             data,
         })
     }
+
+    /// Write the header, including any extra data, to writer.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        writer.write_u32::<BigEndian>(self.magic)?;
+        writer.write_u16::<BigEndian>(self.size)?;
+        writer.write_u16::<BigEndian>(self.version)?;
+        writer.write_u64::<BigEndian>(self.toc_length_compressed)?;
+        writer.write_u64::<BigEndian>(self.toc_length_uncompressed)?;
+        writer.write_u32::<BigEndian>((&self.checksum_alg).into())?;
+        writer.write_all(&self.data)?;
+        Ok(())
+    }
 }
 
 impl fmt::Display for Header {
+    #[allow(clippy::write_with_newline)]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:25}: {}\n", "magic", self.magic)?;
         write!(f, "{:25}: {}\n", "size (header)", self.size)?;
@@ -2,8 +2,20 @@
 mod tests;
 
 pub mod archive;
+pub mod builder;
+pub mod checksum;
+pub mod encoding;
+pub mod error;
+pub mod extract;
 pub mod header;
+pub mod limits;
+pub mod rebuild;
 pub mod toc;
+pub mod verify;
+mod xattr;
 pub use archive::Archive;
+pub use builder::ArchiveBuilder;
+pub use error::{Error, Result};
 pub use header::Header;
+pub use limits::ReadLimits;
 pub use toc::Toc;
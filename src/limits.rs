@@ -0,0 +1,80 @@
+// `serde_derive` expands to code that newer compilers lint against.
+#![allow(non_local_definitions)]
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Errors {
+    #[error("Header is {0} bytes, more than the limit of {1}.")]
+    HeaderTooLarge(u64, u64),
+    #[error("Toc is larger than the limit of {0} bytes.")]
+    TocTooLarge(u64),
+    #[error("Toc has more than the limit of {0} files.")]
+    TooManyFiles(u64),
+    #[error("Toc nests files deeper than the limit of {0}.")]
+    TooDeep(u64),
+    #[error("‘{0}’ is larger than the limit of {1} bytes.")]
+    FileTooLarge(String, u64),
+    #[error("‘{0}’ expands more than {1} times its archived size.")]
+    RatioTooHigh(String, u64),
+    #[error("Extracting more than the limit of {0} bytes in total.")]
+    TotalTooLarge(u64),
+}
+
+/// Limits on what is read from an archive, so that a malicious one fails
+/// with an error instead of exhausting memory or disk.
+///
+/// Sizes are in bytes. The defaults bound the toc, which is kept in memory,
+/// and are generous enough for file data of real archives while still
+/// stopping decompression bombs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReadLimits {
+    pub max_header_size: u64,
+    /// Uncompressed size of the toc.
+    pub max_toc_size: u64,
+    pub max_files: u64,
+    /// How deep directories may be nested.
+    pub max_depth: u64,
+    /// Extracted size of a single file or extended attribute.
+    pub max_file_size: u64,
+    /// Extracted size of all files together.
+    pub max_total_size: u64,
+    /// Extracted size of a file divided by its archived size.
+    pub max_ratio: u64,
+}
+
+impl ReadLimits {
+    /// No limits at all, for archives that are trusted.
+    pub fn unlimited() -> ReadLimits {
+        ReadLimits {
+            max_header_size: u64::MAX,
+            max_toc_size: u64::MAX,
+            max_files: u64::MAX,
+            max_depth: u64::MAX,
+            max_file_size: u64::MAX,
+            max_total_size: u64::MAX,
+            max_ratio: u64::MAX,
+        }
+    }
+
+    /// Most data a single file may expand to, given its archived length.
+    pub fn file_limit(&self, length: u64) -> u64 {
+        self.max_file_size
+            .min(length.max(1).saturating_mul(self.max_ratio))
+    }
+}
+
+impl Default for ReadLimits {
+    fn default() -> ReadLimits {
+        ReadLimits {
+            max_header_size: 4096,
+            max_toc_size: 256 * 1024 * 1024,
+            max_files: 10_000_000,
+            max_depth: 1024,
+            max_file_size: 64 * 1024 * 1024 * 1024,
+            max_total_size: 1024 * 1024 * 1024 * 1024,
+            max_ratio: 10_000,
+        }
+    }
+}
@@ -1,19 +1,40 @@
 extern crate xar;
-use clap::{App, Arg, ArgMatches, SubCommand, AppSettings};
-use failure::{Error, Fail};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::*;
-use xar::Archive;
+use thiserror::Error;
+use xar::checksum::Checksum;
+use xar::encoding::Encoding;
+use xar::error::describe;
+use xar::extract::Extractor;
+use xar::header::ChecksumAlg;
+use xar::limits::ReadLimits;
+use xar::rebuild::Rebuilder;
+use xar::toc::{write_listing, Listed};
+use xar::{Archive, ArchiveBuilder};
 use xmltree::*;
 
-#[derive(Fail, Debug)]
+#[derive(Error, Debug)]
 enum Errors {
-    #[fail(display = "Argument missing.")]
+    #[error("Argument missing.")]
     ArgMissing,
-    #[fail(display = "File ‘{}’ doesn't exist in archive ‘{}’.", _0, _1)]
+    #[error("File ‘{0}’ doesn't exist in archive ‘{1}’.")]
     FileMissing(String, String),
+    #[error("Archive ‘{0}’ failed verification.")]
+    VerifyFailed(String),
+    #[error("Invalid number of jobs ‘{0}’.")]
+    InvalidJobs(String),
+    #[error("Invalid SOURCE_DATE_EPOCH ‘{0}’.")]
+    InvalidSourceDateEpoch(String),
+    #[error("Invalid limit ‘{0}’.")]
+    InvalidLimit(String),
+    #[error("Cannot rebuild ‘{0}’ onto itself.")]
+    RebuildInPlace(String),
 }
 
+type Error = Box<dyn std::error::Error>;
+
 fn main() {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -95,13 +116,242 @@ fn main() {
                         .short("a")
                         .long("all")
                         .help("Recurse into directories."),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .conflicts_with_all(&["long", "null"])
+                        .help("Export listing as JSON."),
+                )
+                .arg(
+                    Arg::with_name("null")
+                        .short("0")
+                        .long("null")
+                        .help("Terminate entries with NUL instead of newline."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Extracts files from a XAR archive.")
+                .arg(
+                    Arg::with_name("ARCHIVE")
+                        .help("The archive to extract, ‘-’ for standard input.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("PATHS")
+                        .help("Only extract these paths.")
+                        .multiple(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("directory")
+                        .short("C")
+                        .long("directory")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Extract into DIR instead of the current directory."),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Read and decode data on N threads."),
+                )
+                .arg(
+                    Arg::with_name("max-size")
+                        .long("max-size")
+                        .takes_value(true)
+                        .value_name("BYTES")
+                        .help("Refuse to extract files larger than BYTES."),
+                )
+                .arg(
+                    Arg::with_name("max-total-size")
+                        .long("max-total-size")
+                        .takes_value(true)
+                        .value_name("BYTES")
+                        .help("Refuse to extract more than BYTES in total."),
+                )
+                .arg(
+                    Arg::with_name("max-ratio")
+                        .long("max-ratio")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Refuse to extract files that expand more than N times."),
+                )
+                .arg(
+                    Arg::with_name("stream")
+                        .long("stream")
+                        .help("Read the archive once from front to back, as for standard input."),
+                )
+                .arg(
+                    Arg::with_name("no-xattrs")
+                        .long("no-xattrs")
+                        .help("Don't apply extended attributes."),
+                )
+                .arg(
+                    Arg::with_name("preserve-permissions")
+                        .short("p")
+                        .long("preserve-permissions")
+                        .overrides_with("no-same-permissions")
+                        .help("Apply modes from the archive as they are, ignoring the umask."),
+                )
+                .arg(
+                    Arg::with_name("no-same-permissions")
+                        .long("no-same-permissions")
+                        .overrides_with("preserve-permissions")
+                        .help("Apply the umask to modes from the archive (default), overriding an earlier -p."),
+                )
+                .arg(
+                    Arg::with_name("same-owner")
+                        .long("same-owner")
+                        .help("Give files the owner and group from the archive."),
+                )
+                .arg(
+                    Arg::with_name("numeric-owner")
+                        .long("numeric-owner")
+                        .help("Use numeric ids instead of user and group names."),
+                )
+                .arg(
+                    Arg::with_name("unsafe-paths")
+                        .long("unsafe-paths")
+                        .help("Allow names with ‘..’ or slashes and writing through symlinks."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("create")
+                .about("Creates a XAR archive.")
+                .arg(
+                    Arg::with_name("file")
+                        .short("f")
+                        .long("file")
+                        .takes_value(true)
+                        .value_name("ARCHIVE")
+                        .required(true)
+                        .help("The archive to create."),
+                )
+                .arg(
+                    Arg::with_name("compression")
+                        .short("z")
+                        .long("compression")
+                        .takes_value(true)
+                        .possible_values(&["none", "gzip", "bzip2", "lzma", "xz"])
+                        .default_value("gzip")
+                        .help("How to compress file data."),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Compress data on N threads."),
+                )
+                .arg(Arg::with_name("reproducible").long("reproducible").help(
+                    "Write the same archive for the same files every time, with \
+                             timestamps clamped to $SOURCE_DATE_EPOCH (or 0).",
+                ))
+                .arg(
+                    Arg::with_name("PATHS")
+                        .help("Files and directories to add.")
+                        .multiple(true)
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Adds files to an existing XAR archive.")
+                .arg(
+                    Arg::with_name("ARCHIVE")
+                        .help("The archive to add to.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("PATHS")
+                        .help("Files and directories to add.")
+                        .multiple(true)
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("compression")
+                        .short("z")
+                        .long("compression")
+                        .takes_value(true)
+                        .possible_values(&["none", "gzip", "bzip2", "lzma", "xz"])
+                        .default_value("gzip")
+                        .help("How to compress file data."),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Compress data on N threads."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rebuild")
+                .about("Rewrites a XAR archive with new compression and checksums.")
+                .arg(
+                    Arg::with_name("IN")
+                        .help("The archive to rebuild.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUT")
+                        .help("Where to write the rebuilt archive.")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("compression")
+                        .short("z")
+                        .long("compression")
+                        .takes_value(true)
+                        .possible_values(&["none", "gzip", "bzip2", "lzma", "xz"])
+                        .default_value("gzip")
+                        .help("How to compress file data."),
+                )
+                .arg(
+                    Arg::with_name("checksum")
+                        .long("checksum")
+                        .takes_value(true)
+                        .possible_values(&["md5", "sha1", "sha256", "sha512"])
+                        .default_value("sha1")
+                        .help("Checksum algorithm for the toc and file data."),
+                )
+                .arg(
+                    Arg::with_name("deduplicate")
+                        .long("deduplicate")
+                        .help("Store identical file data only once."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks the header, toc and file checksums of a XAR archive.")
+                .arg(
+                    Arg::with_name("ARCHIVE")
+                        .help("The archive to verify.")
+                        .required(true)
+                        .index(1),
                 ),
         )
         .get_matches();
 
     match run(&matches) {
         Ok(_) => {}
-        Err(e) => println!("{}", e),
+        Err(e) => {
+            eprintln!("{}", describe(e.as_ref()));
+            std::process::exit(1);
+        }
     }
 }
 
@@ -111,16 +361,21 @@ fn run(matches: &ArgMatches) -> Result<(), Error> {
         ("dump-toc", Some(matches)) => dump_toc(matches),
         ("dump-file", Some(matches)) => dump_file(matches),
         ("list", Some(matches)) => list(matches),
-        (_, None) => default(&matches),
+        ("extract", Some(matches)) => extract(matches),
+        ("create", Some(matches)) => create(matches),
+        ("add", Some(matches)) => add(matches),
+        ("rebuild", Some(matches)) => rebuild(matches),
+        ("verify", Some(matches)) => verify(matches),
+        (_, None) => default(matches),
         (_, _) => unreachable!(),
     }
 }
 
 fn dump_toc(matches: &ArgMatches) -> Result<(), Error> {
     let filename = matches.value_of("FILE").ok_or(Errors::ArgMissing)?;
-    let mut file = File::open(filename)?;
+    let file = File::open(filename)?;
 
-    let archive = Archive::from_read(&mut file)?;
+    let archive = Archive::from_read(file)?;
 
     let stdout = std::io::stdout();
     let handle = stdout.lock();
@@ -136,25 +391,30 @@ fn dump_toc(matches: &ArgMatches) -> Result<(), Error> {
 
 fn dump_file(matches: &ArgMatches) -> Result<(), Error> {
     let archive_name = matches.value_of("ARCHIVE").ok_or(Errors::ArgMissing)?;
-    let mut archive_file = File::open(archive_name)?;
-    let archive = Archive::from_read(&mut archive_file)?;
+    let archive_file = File::open(archive_name)?;
+    let archive = Archive::from_read(archive_file)?;
 
     let filename = matches.value_of("FILE").ok_or(Errors::ArgMissing)?;
-    let path = PathBuf::from(filename);
-    let files = archive.toc().files()?;
+    let file = archive
+        .toc()
+        .lookup(filename)
+        .ok_or_else(|| Errors::FileMissing(filename.into(), archive_name.into()))?;
+    let entry = file.entry()?;
 
-    let _file = files
-        .find(&path)
-        .ok_or(Errors::FileMissing(filename.into(), archive_name.into()))?;
+    if matches.is_present("json") {
+        println!("{}", entry.to_json()?);
+    } else {
+        print!("{}", entry);
+    }
 
     Ok(())
 }
 
 fn dump_header(matches: &ArgMatches) -> Result<(), Error> {
     let filename = matches.value_of("FILE").ok_or(Errors::ArgMissing)?;
-    let mut file = File::open(filename)?;
+    let file = File::open(filename)?;
 
-    let archive = Archive::from_read(&mut file)?;
+    let archive = Archive::from_read(file)?;
 
     if matches.is_present("json") {
         println!("{}", archive.header().to_json()?);
@@ -167,38 +427,247 @@ fn dump_header(matches: &ArgMatches) -> Result<(), Error> {
 
 fn list(matches: &ArgMatches) -> Result<(), Error> {
     let filename = matches.value_of("FILE").ok_or(Errors::ArgMissing)?;
-    let mut file = File::open(filename)?;
+    let file = File::open(filename)?;
 
-    let archive = Archive::from_read(&mut file)?;
+    let archive = Archive::from_read(file)?;
 
-    let long = matches.is_present("long");
     let all = matches.is_present("all");
 
-    let files = archive.toc().files()?;
+    let files = match matches.value_of("PATH") {
+        Some(path) => archive
+            .toc()
+            .lookup(path)
+            .ok_or_else(|| Errors::FileMissing(path.into(), filename.into()))?
+            .files(),
+        None => archive.toc().files()?,
+    };
+
+    let entries = Listed::list(files, all)?;
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string(&entries)?);
+        return Ok(());
+    }
+
+    let terminator = if matches.is_present("null") {
+        '\0'
+    } else {
+        '\n'
+    };
+    let stdout = std::io::stdout();
+    write_listing(
+        &mut stdout.lock(),
+        &entries,
+        matches.is_present("long"),
+        terminator,
+    )?;
+
+    Ok(())
+}
+
+fn extract(matches: &ArgMatches) -> Result<(), Error> {
+    let filename = matches.value_of("ARCHIVE").ok_or(Errors::ArgMissing)?;
+    let limits = read_limits(matches)?;
+
+    if filename == "-" {
+        let stdin = std::io::stdin();
+        let archive = Archive::from_read_with_limits(BufReader::new(stdin.lock()), limits)?;
+        return Ok(extractor(&archive, matches)?.extract_stream()?);
+    }
 
-    list_files(files, all, long)?;
+    let archive = Archive::from_read_with_limits(BufReader::new(File::open(filename)?), limits)?;
+    if matches.is_present("stream") {
+        extractor(&archive, matches)?.extract_stream()?;
+    } else {
+        extractor(&archive, matches)?
+            .reopen_with(|| Ok(BufReader::new(File::open(filename)?)))
+            .extract()?;
+    }
 
     Ok(())
 }
 
-fn list_files(files: xar::toc::Files, recurse: bool, long: bool) -> Result<(), Error> {
-    for file in files.iter() {
-        let attrs = file.attrs();
+fn extractor<'a, R: Read>(
+    archive: &'a Archive<R>,
+    matches: &'a ArgMatches,
+) -> Result<Extractor<'a, R>, Error> {
+    let dest = Path::new(matches.value_of("directory").unwrap_or("."));
+    let paths: Vec<&str> = matches
+        .values_of("PATHS")
+        .map(|paths| paths.collect())
+        .unwrap_or_default();
 
-        if long {
-        } else {
-            if let Some(name) = attrs.name {
-                if recurse && file.path.components().count() != 0 {
-                    println!("{}/{}", file.path.display(), name);
-                } else {
-                    println!("{}", name);
-                }
-            }
-        }
+    Ok(Extractor::new(archive, dest)
+        .jobs(jobs(matches)?)
+        .paths(&paths)
+        .xattrs(!matches.is_present("no-xattrs"))
+        .preserve_permissions(matches.is_present("preserve-permissions"))
+        .same_owner(matches.is_present("same-owner"))
+        .numeric_owner(matches.is_present("numeric-owner"))
+        .unsafe_paths(matches.is_present("unsafe-paths")))
+}
 
-        if recurse {
-            list_files(file.files(), recurse, long)?;
+/// The default limits, with those given on the command line replaced.
+fn read_limits(matches: &ArgMatches) -> Result<ReadLimits, Error> {
+    let limit = |name| -> Result<Option<u64>, Error> {
+        match matches.value_of(name) {
+            Some(value) => Ok(Some(
+                value
+                    .parse()
+                    .map_err(|_| Errors::InvalidLimit(value.into()))?,
+            )),
+            None => Ok(None),
         }
+    };
+
+    let mut limits = ReadLimits::default();
+    if let Some(size) = limit("max-size")? {
+        limits.max_file_size = size;
+    }
+    if let Some(size) = limit("max-total-size")? {
+        limits.max_total_size = size;
+    }
+    if let Some(ratio) = limit("max-ratio")? {
+        limits.max_ratio = ratio;
+    }
+    Ok(limits)
+}
+
+fn create(matches: &ArgMatches) -> Result<(), Error> {
+    let filename = matches.value_of("file").ok_or(Errors::ArgMissing)?;
+    let paths = matches.values_of("PATHS").ok_or(Errors::ArgMissing)?;
+
+    let compression = matches
+        .value_of("compression")
+        .and_then(Encoding::from_name)
+        .ok_or(Errors::ArgMissing)?;
+
+    let mut builder = ArchiveBuilder::new();
+    builder.encoding(compression);
+    builder.jobs(jobs(matches)?);
+    if matches.is_present("reproducible") {
+        let epoch = match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) => epoch
+                .parse()
+                .map_err(|_| Errors::InvalidSourceDateEpoch(epoch))?,
+            Err(_) => 0,
+        };
+        builder.reproducible(epoch)?;
+    }
+    for path in paths {
+        builder.add_path(path)?;
+    }
+
+    let mut file = BufWriter::new(File::create(filename)?);
+    builder.write(&mut file)?;
+    file.flush()?;
+
+    Ok(())
+}
+
+fn add(matches: &ArgMatches) -> Result<(), Error> {
+    let filename = matches.value_of("ARCHIVE").ok_or(Errors::ArgMissing)?;
+    let paths = matches.values_of("PATHS").ok_or(Errors::ArgMissing)?;
+
+    let compression = matches
+        .value_of("compression")
+        .and_then(Encoding::from_name)
+        .ok_or(Errors::ArgMissing)?;
+
+    let archive = Archive::from_read(BufReader::new(File::open(filename)?))?;
+
+    let mut builder = ArchiveBuilder::new();
+    builder.encoding(compression);
+    // new data is checksummed like the existing data, where possible.
+    let checksum_alg = &archive.header().checksum_alg;
+    if Checksum::new(checksum_alg).is_ok() {
+        builder.checksum_alg(checksum_alg.clone());
+    }
+    builder.jobs(jobs(matches)?);
+    for path in paths {
+        builder.add_path(path)?;
+    }
+
+    // written next to the archive and moved over it once complete. It is
+    // removed when dropped before that.
+    let parent = match Path::new(filename).parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    let temporary = tempfile::NamedTempFile::new_in(parent)?;
+    std::fs::set_permissions(temporary.path(), std::fs::metadata(filename)?.permissions())?;
+    let mut file = BufWriter::new(temporary);
+    archive.append(&builder, &mut file)?;
+    let temporary = file.into_inner().map_err(|e| e.into_error())?;
+    temporary.persist(filename)?;
+
+    Ok(())
+}
+
+fn rebuild(matches: &ArgMatches) -> Result<(), Error> {
+    let input = matches.value_of("IN").ok_or(Errors::ArgMissing)?;
+    let output = matches.value_of("OUT").ok_or(Errors::ArgMissing)?;
+
+    let compression = matches
+        .value_of("compression")
+        .and_then(Encoding::from_name)
+        .ok_or(Errors::ArgMissing)?;
+    let checksum = matches
+        .value_of("checksum")
+        .map(ChecksumAlg::from_name)
+        .ok_or(Errors::ArgMissing)?;
+
+    if Path::new(output).exists()
+        && Path::new(input).canonicalize()? == Path::new(output).canonicalize()?
+    {
+        return Err(Errors::RebuildInPlace(input.into()).into());
+    }
+
+    let archive = Archive::from_read(BufReader::new(File::open(input)?))?;
+
+    let mut file = BufWriter::new(File::create(output)?);
+    Rebuilder::new(&archive)
+        .encoding(compression)
+        .checksum_alg(checksum)
+        .deduplicate(matches.is_present("deduplicate"))
+        .write(&mut file)?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Number of threads to use, one unless asked for more.
+fn jobs(matches: &ArgMatches) -> Result<usize, Error> {
+    match matches.value_of("jobs") {
+        Some(jobs) => Ok(jobs.parse().map_err(|_| Errors::InvalidJobs(jobs.into()))?),
+        None => Ok(1),
+    }
+}
+
+fn verify(matches: &ArgMatches) -> Result<(), Error> {
+    let filename = matches.value_of("ARCHIVE").ok_or(Errors::ArgMissing)?;
+    let file = File::open(filename)?;
+
+    let archive = Archive::from_read(file)?;
+    let mut ok = true;
+
+    if let Err(e) = archive.header().check() {
+        println!("header: {}", describe(&e));
+        ok = false;
+    }
+
+    if let Err(e) = archive.verify_toc_checksum() {
+        println!("toc: {}", describe(&e));
+        ok = false;
+    }
+
+    for failure in archive.verify_files()? {
+        println!("{}", failure);
+        ok = false;
+    }
+
+    if !ok {
+        return Err(Errors::VerifyFailed(filename.into()).into());
     }
 
     Ok(())
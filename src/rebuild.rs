@@ -0,0 +1,205 @@
+use crate::builder::{ArchiveBuilder, Heap};
+use crate::checksum::{self, to_hex, Checksum, ChecksumReader};
+use crate::encoding::Encoding;
+use crate::header::{ChecksumAlg, Header};
+use crate::limits;
+use crate::toc::{self, FileData};
+use crate::Archive;
+use crate::Error;
+use std::collections::HashMap;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use thiserror::Error;
+use xmltree::Element;
+
+#[derive(Error, Debug)]
+pub enum Errors {
+    #[error("Data of ‘{0}’ does not match its checksum.")]
+    ChecksumMismatch(String),
+}
+
+/// Children of `<data>` and `<ea>` elements that describe the data itself.
+const DATA_ELEMENTS: &[&str] = &[
+    "length",
+    "offset",
+    "size",
+    "encoding",
+    "extracted-checksum",
+    "archived-checksum",
+];
+
+/// Writes a copy of an archive with all data encoded and checksummed anew.
+///
+/// Only data referenced from the toc is copied, so gaps and orphaned data
+/// in the heap are dropped. Data shared by several entries stays shared.
+pub struct Rebuilder<'a, R: Read + Seek> {
+    archive: &'a Archive<R>,
+    /// Encodes the data, with the encoding and checksum algorithm to use.
+    builder: ArchiveBuilder,
+    checksum_alg: ChecksumAlg,
+    deduplicate: bool,
+    /// New data elements, by offset and length of the old data.
+    rebuilt: HashMap<(u64, u64), Element>,
+    /// New data elements, by length and checksum of their archived data.
+    identical: HashMap<(u64, Vec<u8>), Vec<Element>>,
+}
+
+impl<'a, R: Read + Seek> Rebuilder<'a, R> {
+    pub fn new(archive: &'a Archive<R>) -> Self {
+        Rebuilder {
+            archive,
+            builder: ArchiveBuilder::new(),
+            checksum_alg: ChecksumAlg::SHA1,
+            deduplicate: false,
+            rebuilt: HashMap::new(),
+            identical: HashMap::new(),
+        }
+    }
+
+    /// Set the encoding of all data, gzip by default.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.builder.encoding(encoding);
+        self
+    }
+
+    /// Set the checksum algorithm of the toc and all data, sha1 by default.
+    pub fn checksum_alg(mut self, alg: ChecksumAlg) -> Self {
+        self.builder.checksum_alg(alg.clone());
+        self.checksum_alg = alg;
+        self
+    }
+
+    /// Store identical data only once, even if it was stored several times
+    /// before.
+    pub fn deduplicate(mut self, deduplicate: bool) -> Self {
+        self.deduplicate = deduplicate;
+        self
+    }
+
+    /// Write the rebuilt archive.
+    pub fn write<W: Write>(mut self, writer: &mut W) -> Result<(), Error> {
+        let checksum_size = Checksum::new(&self.checksum_alg)?.size();
+        let mut heap = Heap::new(checksum_size as u64)?;
+
+        let mut xar = self.archive.toc().data().clone();
+        let toc = xar.get_mut_child("toc").ok_or(toc::Errors::NoTocElement)?;
+        let checksum = self.builder.checksum_element(checksum_size);
+        match toc.children.iter().position(|c| c.name == "checksum") {
+            Some(pos) => toc.children[pos] = checksum,
+            None => toc.children.insert(0, checksum),
+        }
+        self.rebuild_files(toc, &mut heap, Path::new(""))?;
+
+        let mut toc_data = Vec::new();
+        xar.write(&mut toc_data)?;
+        let toc_compressed = Encoding::Gzip.encode(&toc_data)?;
+
+        let toc_checksum = checksum::digest(&self.checksum_alg, &toc_compressed)?;
+
+        let header = Header::new(
+            toc_compressed.len() as u64,
+            toc_data.len() as u64,
+            self.checksum_alg,
+        );
+        header.write(writer)?;
+        writer.write_all(&toc_compressed)?;
+        writer.write_all(&toc_checksum)?;
+        heap.copy_to(writer)?;
+
+        Ok(())
+    }
+
+    fn rebuild_files(
+        &mut self,
+        parent: &mut Element,
+        heap: &mut Heap,
+        path: &Path,
+    ) -> Result<(), Error> {
+        for file in parent.children.iter_mut().filter(|c| c.name == "file") {
+            let name = file
+                .get_child("name")
+                .and_then(|name| name.text.clone())
+                .unwrap_or_default();
+            let path = path.join(name);
+
+            for data in file
+                .children
+                .iter_mut()
+                .filter(|c| c.name == "data" || c.name == "ea")
+            {
+                let old = FileData::parse(data)?;
+                let new = match self.rebuilt.get(&(old.offset, old.length)) {
+                    Some(new) => new.clone(),
+                    None => {
+                        let new = self.rebuild_data(&old, heap, &path)?;
+                        self.rebuilt.insert((old.offset, old.length), new.clone());
+                        new
+                    }
+                };
+
+                data.children
+                    .retain(|c| !DATA_ELEMENTS.contains(&c.name.as_str()));
+                data.children.extend(new.children);
+            }
+
+            self.rebuild_files(file, heap, &path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Decode the old data and append it to the new heap, encoded anew,
+    /// checking it against its extracted checksum if it has one we know.
+    /// Returns the `<data>` element describing it.
+    fn rebuild_data(
+        &mut self,
+        old: &FileData,
+        heap: &mut Heap,
+        path: &Path,
+    ) -> Result<Element, Error> {
+        let limits = self.archive.limits();
+        let name = || path.display().to_string();
+        let limit = limits.file_limit(old.length);
+
+        let raw = self.archive.open_heap(old.offset, old.length)?;
+        let mut reader = ChecksumReader {
+            reader: old.encoding.decoder(raw)?.take(limit.saturating_add(1)),
+            checksum: old
+                .extracted_checksum
+                .as_ref()
+                .and_then(|checksum| Checksum::new(&checksum.alg).ok()),
+        };
+        let offset = heap.end();
+        let mut encoded = self.builder.encode(&mut reader, &mut *heap)?;
+        encoded.offset = offset;
+
+        if encoded.size > limits.max_file_size {
+            return Err(limits::Errors::FileTooLarge(name(), limits.max_file_size).into());
+        }
+        if encoded.size > limit {
+            return Err(limits::Errors::RatioTooHigh(name(), limits.max_ratio).into());
+        }
+        if let (Some(expected), Some(actual)) = (&old.extracted_checksum, reader.checksum) {
+            if to_hex(&actual.finish()) != expected.digest {
+                return Err(Errors::ChecksumMismatch(name()).into());
+            }
+        }
+
+        let key = (encoded.length, encoded.archived_checksum.clone());
+        if self.deduplicate {
+            for element in self.identical.get(&key).into_iter().flatten() {
+                let data = FileData::parse(element)?;
+                if heap.same(data.offset, offset, encoded.length)? {
+                    heap.truncate(offset)?;
+                    return Ok(element.clone());
+                }
+            }
+        }
+
+        let element = self.builder.data_element(&encoded);
+        if self.deduplicate {
+            self.identical.entry(key).or_default().push(element.clone());
+        }
+        Ok(element)
+    }
+}
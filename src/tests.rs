@@ -1,6 +1,23 @@
+// The fixtures keep their original `&'static` declarations.
+#![allow(clippy::redundant_static_lifetimes)]
+
+use crate::builder;
+use crate::checksum;
+use crate::encoding::Encoding;
+use crate::extract::{self, Extractor};
 use crate::header;
-use crate::Header;
-use std::io::Cursor;
+use crate::limits::{self, ReadLimits};
+use crate::rebuild::Rebuilder;
+use crate::toc::{
+    mode_string, write_listing, Device, Errors, FileEntry, FileType, Hardlink, LinkType, Listed,
+};
+use crate::verify::Failure;
+use crate::xattr;
+use crate::{Archive, ArchiveBuilder, Error, Header};
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use xmltree::Element;
 
 const NULL_XAR: &'static [u8] =
     include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/data/null.xar"));
@@ -15,6 +32,13 @@ const NULL_TOC_SHA512_XAR: &'static [u8] = include_bytes!(concat!(
     "/data/null_toc_sha512.xar"
 ));
 
+const FILES_XAR: &'static [u8] =
+    include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/data/files.xar"));
+
+#[cfg(all(feature = "bzip2", feature = "xz"))]
+const ENCODINGS_XAR: &'static [u8] =
+    include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/data/encodings.xar"));
+
 #[test]
 fn test_can_load_header() {
     let mut cursor = Cursor::new(NULL_XAR);
@@ -55,7 +79,7 @@ fn test_can_load_header_toc_cksum_sha512() {
 fn test_header_load_fails_with_invalid_magic() {
     for i in 0..4 {
         let mut copy: Vec<u8> = NULL_XAR.into();
-        copy[i] = copy[i] + 1;
+        copy[i] += 1;
         let mut cursor = Cursor::new(&copy);
         let header = Header::from_read(&mut cursor).unwrap();
         assert_eq!(header.check(), Err(header::Error::MagicError));
@@ -71,3 +95,1547 @@ fn test_header_load_fails_with_invalid_version() {
     let header = Header::from_read(&mut cursor).unwrap();
     assert_eq!(header.check(), Err(header::Error::Version(0)));
 }
+
+#[test]
+fn test_can_extract_all() {
+    let cursor = Cursor::new(FILES_XAR);
+    let archive = Archive::from_read(cursor).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    archive.extract_all(dest.path()).unwrap();
+
+    let dir = dest.path().join("dir");
+    assert!(dir.is_dir());
+    assert_eq!(
+        fs::read_to_string(dir.join("hello.txt")).unwrap(),
+        "Hello, world!\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("lorem.txt")).unwrap(),
+        "Lorem ipsum dolor sit amet, consectetur adipiscing elit.\n".repeat(20)
+    );
+    assert_eq!(
+        fs::read_link(dir.join("link")).unwrap(),
+        Path::new("hello.txt")
+    );
+    assert_eq!(fs::read(dest.path().join("empty")).unwrap().len(), 0);
+}
+
+#[test]
+fn test_can_extract_paths() {
+    let cursor = Cursor::new(FILES_XAR);
+    let archive = Archive::from_read(cursor).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    archive.extract(dest.path(), &["dir/hello.txt"]).unwrap();
+
+    assert!(dest.path().join("dir/hello.txt").is_file());
+    assert!(!dest.path().join("dir/lorem.txt").exists());
+    assert!(!dest.path().join("empty").exists());
+}
+
+#[test]
+fn test_builder_roundtrip() {
+    let mut builder = ArchiveBuilder::new();
+    builder
+        .add_data("dir/hello.txt", b"Hello, world!\n".to_vec())
+        .unwrap();
+    builder.add_data("empty", Vec::new()).unwrap();
+    builder.add_directory("dir/sub").unwrap();
+    builder.add_symlink("dir/link", "hello.txt").unwrap();
+
+    let mut data = Vec::new();
+    builder.write(&mut data).unwrap();
+
+    let cursor = Cursor::new(&data);
+    let archive = Archive::from_read(cursor).unwrap();
+    assert!(archive.header().check().is_ok());
+    assert_eq!(archive.toc().checksum_type().unwrap(), "sha1");
+
+    // the toc checksum is stored at the start of the heap.
+    let toc_start = archive.header().size as usize;
+    let heap_start = archive.heap_offset() as usize;
+    let toc_checksum =
+        checksum::digest(&archive.header().checksum_alg, &data[toc_start..heap_start]).unwrap();
+    assert_eq!(&data[heap_start..heap_start + 20], &toc_checksum[..]);
+
+    let dest = tempfile::tempdir().unwrap();
+    archive.extract_all(dest.path()).unwrap();
+    assert_eq!(
+        fs::read_to_string(dest.path().join("dir/hello.txt")).unwrap(),
+        "Hello, world!\n"
+    );
+    assert!(dest.path().join("dir/sub").is_dir());
+    assert_eq!(
+        fs::read_link(dest.path().join("dir/link")).unwrap(),
+        Path::new("hello.txt")
+    );
+    assert_eq!(fs::read(dest.path().join("empty")).unwrap().len(), 0);
+}
+
+#[test]
+fn test_builder_from_disk() {
+    let source = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("tree")).unwrap();
+    fs::write(source.path().join("tree/a.txt"), "a").unwrap();
+    fs::create_dir(source.path().join("tree/b")).unwrap();
+    fs::write(source.path().join("tree/b/c.txt"), "c".repeat(1000)).unwrap();
+
+    let mut builder = ArchiveBuilder::new();
+    builder
+        .add_path_as(source.path().join("tree"), "tree")
+        .unwrap();
+    let mut data = Vec::new();
+    builder.write(&mut data).unwrap();
+
+    let cursor = Cursor::new(&data);
+    let archive = Archive::from_read(cursor).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    archive.extract_all(dest.path()).unwrap();
+    assert_eq!(
+        fs::read_to_string(dest.path().join("tree/a.txt")).unwrap(),
+        "a"
+    );
+    assert_eq!(
+        fs::read_to_string(dest.path().join("tree/b/c.txt")).unwrap(),
+        "c".repeat(1000)
+    );
+}
+
+#[test]
+fn test_can_open_data() {
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    assert_eq!(
+        archive.heap_offset(),
+        28 + archive.header().toc_length_compressed
+    );
+
+    let files = archive.toc().files().unwrap();
+    let dir = files.iter().next().unwrap().files();
+    let hello = dir.iter().next().unwrap();
+    assert_eq!(hello.name().unwrap(), "hello.txt");
+
+    let mut data = String::new();
+    let mut reader = archive.open_data(&hello).unwrap();
+    reader.read_to_string(&mut data).unwrap();
+    assert_eq!(data, "Hello, world!\n");
+
+    // the reader is exclusive while it is open.
+    assert!(archive.open_data(&hello).is_err());
+    drop(reader);
+    assert!(archive.open_data(&hello).is_ok());
+}
+
+#[test]
+#[cfg(all(feature = "bzip2", feature = "xz"))]
+fn test_can_extract_encodings() {
+    let archive = Archive::from_read(Cursor::new(ENCODINGS_XAR)).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    archive.extract_all(dest.path()).unwrap();
+
+    let expected = "The quick brown fox jumps over the lazy dog.\n".repeat(10);
+    for name in &["none", "gzip", "bzip2", "lzma", "xz"] {
+        let path = dest.path().join(format!("{}.txt", name));
+        assert_eq!(fs::read_to_string(path).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_builder_encodings_roundtrip() {
+    let content = b"The quick brown fox jumps over the lazy dog.\n".repeat(10);
+    for encoding in Encoding::supported() {
+        let mut builder = ArchiveBuilder::new();
+        builder.encoding(encoding.clone());
+        builder.add_data("file", content.clone()).unwrap();
+        let mut data = Vec::new();
+        builder.write(&mut data).unwrap();
+
+        let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+        let files = archive.toc().files().unwrap();
+        let file = files.iter().next().unwrap();
+        let entry = file.entry().unwrap();
+        assert_eq!(entry.data.as_ref().unwrap().encoding, encoding);
+
+        let mut decoded = Vec::new();
+        encoding
+            .decoder(archive.open_data(&file).unwrap())
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, content);
+    }
+}
+
+#[test]
+fn test_unknown_encoding_fails() {
+    let encoding = Encoding::from_style("application/x-unknown");
+    assert_eq!(encoding, Encoding::Other("application/x-unknown".into()));
+    assert!(!encoding.is_supported());
+    assert!(encoding.decoder(Cursor::new(Vec::new())).is_err());
+    assert!(encoding.encode(b"data").is_err());
+}
+
+#[test]
+fn test_verify_toc_checksum() {
+    for data in &[
+        NULL_XAR,
+        NULL_TOC_SHA256_XAR,
+        NULL_TOC_SHA512_XAR,
+        FILES_XAR,
+    ] {
+        let archive = Archive::from_read(Cursor::new(data)).unwrap();
+        archive.verify_toc_checksum().unwrap();
+    }
+}
+
+#[test]
+fn test_verify_toc_checksum_fails_when_corrupted() {
+    // flip a bit in the stored checksum.
+    let mut copy: Vec<u8> = NULL_XAR.into();
+    let heap = 28 + 256 + 97;
+    copy[heap] ^= 1;
+    let archive = Archive::from_read(Cursor::new(&copy)).unwrap();
+    let error = archive.verify_toc_checksum().unwrap_err();
+    let message = crate::error::describe(&error);
+    assert!(message.starts_with("Cannot read archive: "));
+    assert!(message.contains("Toc checksum mismatch: expected "));
+
+    // flip a bit in the trailer of the toc, which the zlib stream itself
+    // already catches when reading it.
+    let mut copy: Vec<u8> = NULL_XAR.into();
+    copy[heap - 1] ^= 1;
+    assert!(Archive::from_read(Cursor::new(&copy)).is_err());
+}
+
+#[test]
+fn test_verify_toc_checksum_algorithms() {
+    let algs = vec![
+        header::ChecksumAlg::SHA1,
+        header::ChecksumAlg::MD5,
+        header::ChecksumAlg::SHA256,
+        header::ChecksumAlg::SHA512,
+        header::ChecksumAlg::Other("sha256".into()),
+    ];
+
+    for alg in algs {
+        let mut builder = ArchiveBuilder::new();
+        builder.checksum_alg(alg.clone());
+        builder.add_data("file", b"data".to_vec()).unwrap();
+        let mut data = Vec::new();
+        builder.write(&mut data).unwrap();
+
+        let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+        assert!(archive.header().check().is_ok());
+        assert_eq!(archive.header().checksum_alg, alg);
+        archive.verify_toc_checksum().unwrap();
+    }
+}
+
+#[test]
+fn test_verify_files() {
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    assert_eq!(archive.verify_files().unwrap(), vec![]);
+}
+
+#[test]
+fn test_verify_files_reports_corruption() {
+    // hello.txt is stored uncompressed right after the toc checksum.
+    let mut copy: Vec<u8> = FILES_XAR.into();
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    copy[archive.heap_offset() as usize + 20] ^= 1;
+
+    let archive = Archive::from_read(Cursor::new(&copy)).unwrap();
+    let failures = archive.verify_files().unwrap();
+    assert_eq!(failures.len(), 2);
+    match &failures[0] {
+        Failure::ArchivedChecksum { path, expected, .. } => {
+            assert_eq!(path, "dir/hello.txt");
+            assert_eq!(expected, "09fac8dbfd27bd9b4d23a00eb648aa751789536d");
+        }
+        other => panic!("unexpected failure {:?}", other),
+    }
+    match &failures[1] {
+        Failure::ExtractedChecksum { path, .. } => assert_eq!(path, "dir/hello.txt"),
+        other => panic!("unexpected failure {:?}", other),
+    }
+}
+
+#[test]
+fn test_verify_files_checks_xattrs() {
+    // sha1 of "hello".
+    let toc = r#"<file id="1">
+            <name>file.txt</name><type>file</type>
+            <ea id="2">
+                <name>user.comment</name>
+                <length>5</length><offset>0</offset><size>5</size>
+                <encoding style="application/octet-stream"/>
+                <archived-checksum style="sha1">aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d</archived-checksum>
+                <extracted-checksum style="sha1">aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d</extracted-checksum>
+            </ea>
+        </file>"#;
+    let data = archive_from_toc(toc, b"hello");
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+    assert_eq!(archive.verify_files().unwrap(), vec![]);
+
+    let data = archive_from_toc(toc, b"hellx");
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+    let failures = archive.verify_files().unwrap();
+    assert_eq!(failures.len(), 2);
+    let path = "file.txt (extended attribute user.comment)";
+    assert!(matches!(&failures[0], Failure::ArchivedChecksum { path: p, .. } if p == path));
+    assert!(matches!(&failures[1], Failure::ExtractedChecksum { path: p, .. } if p == path));
+}
+
+#[test]
+fn test_file_entry_parses_all_metadata() {
+    let archive = Archive::from_read(Cursor::new(NULL_XAR)).unwrap();
+    let files = archive.toc().files().unwrap();
+    let dev = files.iter().next().unwrap();
+    let dev_files = dev.files();
+    let null = dev_files.iter().next().unwrap().entry().unwrap();
+
+    assert_eq!(null.id, Some(2));
+    assert_eq!(null.name.as_deref(), Some("null"));
+    assert_eq!(null.ftype, Some(FileType::CharacterSpecial));
+    assert_eq!(null.mode, Some(0o666));
+    assert_eq!(null.user.as_deref(), Some("root"));
+    assert_eq!(null.group.as_deref(), Some("wheel"));
+    assert_eq!(null.uid, Some(0));
+    assert_eq!(null.gid, Some(0));
+    assert_eq!(null.inode, Some(308));
+    assert_eq!(null.deviceno, Some(762335088));
+    assert_eq!(null.device, Some(Device { major: 3, minor: 2 }));
+    assert_eq!(
+        null.mtime.unwrap().to_rfc3339(),
+        "2019-06-07T22:38:33+00:00"
+    );
+    assert_eq!(
+        null.atime.unwrap().to_rfc3339(),
+        "2019-06-07T19:40:33+00:00"
+    );
+    assert!(null.data.is_none());
+
+    let json = serde_json::to_string(&null).unwrap();
+    let parsed: FileEntry = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed, null);
+}
+
+#[test]
+fn test_file_entry_parses_data_and_xattrs() {
+    let element = Element::parse(
+        r#"<file id="7">
+            <data>
+                <length>10</length><offset>20</offset><size>30</size>
+                <encoding style="application/x-bzip2"/>
+                <archived-checksum style="sha1">AABB</archived-checksum>
+                <extracted-checksum style="md5">ccdd</extracted-checksum>
+            </data>
+            <ea id="0">
+                <name>user.comment</name>
+                <length>5</length><offset>40</offset><size>5</size>
+                <encoding style="application/octet-stream"/>
+            </ea>
+            <flags><UserNoDump/></flags>
+            <FinderCreateTime><nanoseconds>0</nanoseconds></FinderCreateTime>
+            <type>file</type>
+            <name>data.bin</name>
+        </file>"#
+            .as_bytes(),
+    )
+    .unwrap();
+    let entry = FileEntry::parse(&element).unwrap();
+
+    let data = entry.data.unwrap();
+    assert_eq!((data.length, data.offset, data.size), (10, 20, 30));
+    assert_eq!(data.encoding, Encoding::Bzip2);
+    let archived = data.archived_checksum.unwrap();
+    assert_eq!(archived.alg, header::ChecksumAlg::SHA1);
+    assert_eq!(archived.digest, "aabb");
+    assert_eq!(
+        data.extracted_checksum.unwrap().alg,
+        header::ChecksumAlg::MD5
+    );
+
+    assert_eq!(entry.flags, vec!["UserNoDump".to_string()]);
+    assert_eq!(entry.xattrs.len(), 1);
+    assert_eq!(entry.xattrs[0].name, "user.comment");
+    assert_eq!(entry.xattrs[0].data.offset, 40);
+}
+
+#[test]
+fn test_file_entry_reports_invalid_fields() {
+    let parse = |xml: &str| FileEntry::parse(&Element::parse(xml.as_bytes()).unwrap());
+
+    match parse("<file><mode>0999</mode></file>") {
+        Err(Errors::FileElementInvalid("mode")) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    match parse("<file><mtime>yesterday</mtime></file>") {
+        Err(Errors::FileElementInvalid("mtime")) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    match parse("<file><type>teleporter</type></file>") {
+        Err(Errors::UnknownFileType(name)) => assert_eq!(name, "teleporter"),
+        other => panic!("unexpected {:?}", other),
+    }
+    match parse("<file><data><offset>1</offset></data></file>") {
+        Err(Errors::FileDataElementInvalid("length")) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_lookup_paths() {
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    let toc = archive.toc();
+
+    let hello = toc.lookup("dir/hello.txt").unwrap();
+    assert_eq!(hello.name(), Some("hello.txt"));
+    assert_eq!(hello.path, Path::new("dir"));
+    assert_eq!(hello.entry().unwrap().id, Some(2));
+
+    for path in &[
+        "/dir/hello.txt",
+        "./dir/./hello.txt",
+        "dir/../dir/hello.txt",
+    ] {
+        assert_eq!(toc.lookup(path).unwrap().name(), Some("hello.txt"));
+    }
+
+    assert_eq!(toc.lookup("empty").unwrap().name(), Some("empty"));
+    assert_eq!(toc.lookup("dir").unwrap().name(), Some("dir"));
+    assert!(toc.lookup("missing").is_none());
+    assert!(toc.lookup("dir/missing").is_none());
+    assert!(toc.lookup("dir/hello.txt/child").is_none());
+    assert!(toc.lookup("../dir").is_none());
+    assert!(toc.lookup("/").is_none());
+
+    // lookups relative to a directory.
+    let dir = toc.lookup("dir").unwrap().files();
+    assert_eq!(dir.find(Path::new("link")).unwrap().name(), Some("link"));
+}
+
+#[test]
+fn test_lookup_by_id() {
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    let toc = archive.toc();
+
+    let lorem = toc.file_by_id(3).unwrap();
+    assert_eq!(lorem.name(), Some("lorem.txt"));
+    assert_eq!(lorem.path, Path::new("dir"));
+    assert_eq!(toc.file_by_id(5).unwrap().name(), Some("empty"));
+    assert!(toc.file_by_id(99).is_none());
+}
+
+#[test]
+fn test_listing() {
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    let listed = Listed::list(archive.toc().files().unwrap(), true).unwrap();
+    let paths: Vec<_> = listed.iter().map(|listed| listed.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        ["dir", "dir/hello.txt", "dir/lorem.txt", "dir/link", "empty"]
+    );
+
+    let mut long = Vec::new();
+    write_listing(&mut long, &listed, true, '\n').unwrap();
+    assert_eq!(
+        String::from_utf8(long).unwrap(),
+        "drwxr-xr-x xar      staff             0 2019-06-08 10:21 dir\n\
+         -rw-r--r-- xar      staff            14 2019-06-08 10:21 dir/hello.txt\n\
+         -rw-r--r-- xar      staff          1140 2019-06-08 10:21 dir/lorem.txt\n\
+         lrwxr-xr-x xar      staff             0 2019-06-08 10:21 dir/link -> hello.txt\n\
+         -rw-r--r-- xar      staff             0 2019-06-08 10:21 empty\n"
+    );
+
+    let mut short = Vec::new();
+    write_listing(&mut short, &listed, false, '\0').unwrap();
+    assert_eq!(
+        short,
+        b"dir\0dir/hello.txt\0dir/lorem.txt\0dir/link\0empty\0"
+    );
+
+    // unknown fields are shown as such.
+    let unknown = Listed {
+        path: "unknown".into(),
+        entry: FileEntry::new(),
+    };
+    assert_eq!(
+        unknown.long_row(),
+        "?????????? ?        ?                 0 ????-??-?? ??:?? unknown"
+    );
+
+    // only the top level without recursing, with paths flattened into
+    // the entries in json.
+    let listed = Listed::list(archive.toc().files().unwrap(), false).unwrap();
+    let json = serde_json::to_value(&listed).unwrap();
+    let json = json.as_array().unwrap();
+    assert_eq!(json.len(), 2);
+    assert_eq!(json[0]["path"], "dir");
+    assert_eq!(json[0]["name"], "dir");
+    assert_eq!(json[0]["ftype"], "Directory");
+    assert_eq!(json[0]["mode"], 0o755);
+    assert_eq!(json[0]["user"], "xar");
+    assert_eq!(json[0]["uid"], 501);
+    assert_eq!(json[1]["path"], "empty");
+    assert!(json[1]["data"].is_null());
+}
+
+#[test]
+fn test_mode_string() {
+    assert_eq!(mode_string(0o755), "rwxr-xr-x");
+    assert_eq!(mode_string(0o640), "rw-r-----");
+    assert_eq!(mode_string(0o4755), "rwsr-xr-x");
+    assert_eq!(mode_string(0o2644), "rw-r-Sr--");
+    assert_eq!(mode_string(0o1777), "rwxrwxrwt");
+}
+
+#[test]
+fn test_file_entry_display_and_json() {
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    let entry = archive
+        .toc()
+        .lookup("dir/lorem.txt")
+        .unwrap()
+        .entry()
+        .unwrap();
+
+    let display = entry.to_string();
+    assert!(display.contains("type                     : file\n"));
+    assert!(display.contains("mode                     : 0644 (rw-r--r--)\n"));
+    assert!(display.contains("user                     : xar (501)\n"));
+    assert!(display.contains("data encoding            : application/x-gzip\n"));
+    assert!(!display.contains("link"));
+
+    let json: FileEntry = serde_json::from_str(&entry.to_json().unwrap()).unwrap();
+    assert_eq!(json, entry);
+}
+
+#[test]
+fn test_file_entry_parses_link_types() {
+    let parse = |xml: &str| FileEntry::parse(&Element::parse(xml.as_bytes()).unwrap());
+
+    let original = parse(r#"<file><type link="original">hardlink</type></file>"#).unwrap();
+    assert_eq!(original.ftype, Some(FileType::Hardlink));
+    assert_eq!(original.hardlink, Some(Hardlink::Original));
+
+    let link = parse(r#"<file><type link="3">hardlink</type></file>"#).unwrap();
+    assert_eq!(link.hardlink, Some(Hardlink::Of(3)));
+
+    let symlink =
+        parse(r#"<file><type>symlink</type><link type="directory">lib</link></file>"#).unwrap();
+    assert_eq!(symlink.link.as_deref(), Some("lib"));
+    assert_eq!(symlink.link_type, Some(LinkType::Directory));
+
+    assert!(parse(r#"<file><link type="bogus">lib</link></file>"#).is_err());
+    assert!(parse(r#"<file><type link="bogus">hardlink</type></file>"#).is_err());
+
+    for name in &[
+        "file",
+        "directory",
+        "symlink",
+        "hardlink",
+        "fifo",
+        "socket",
+        "character special",
+        "block special",
+        "whiteout",
+    ] {
+        assert_eq!(FileType::from_name(name).unwrap().name(), *name);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_builder_special_files() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileTypeExt;
+
+    let source = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("tree")).unwrap();
+    fs::create_dir(source.path().join("tree/dir")).unwrap();
+    std::os::unix::fs::symlink("dir", source.path().join("tree/link")).unwrap();
+    let fifo = CString::new(source.path().join("tree/fifo").as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+    let mut builder = ArchiveBuilder::new();
+    builder
+        .add_path_as(source.path().join("tree"), "tree")
+        .unwrap();
+    let mut data = Vec::new();
+    builder.write(&mut data).unwrap();
+
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+    let toc = archive.toc();
+    let entry = |path| toc.lookup(path).unwrap().entry().unwrap();
+    assert_eq!(entry("tree/fifo").ftype, Some(FileType::Fifo));
+    assert_eq!(entry("tree/link").link_type, Some(LinkType::Directory));
+
+    let dest = tempfile::tempdir().unwrap();
+    archive.extract_all(dest.path()).unwrap();
+    let metadata = fs::symlink_metadata(dest.path().join("tree/fifo")).unwrap();
+    assert!(metadata.file_type().is_fifo());
+    assert_eq!(
+        fs::read_link(dest.path().join("tree/link")).unwrap(),
+        Path::new("dir")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hardlinks_roundtrip() {
+    use std::os::unix::fs::MetadataExt;
+
+    let source = tempfile::tempdir().unwrap();
+    fs::create_dir_all(source.path().join("tree/sub")).unwrap();
+    fs::write(source.path().join("tree/a.txt"), "shared").unwrap();
+    fs::hard_link(
+        source.path().join("tree/a.txt"),
+        source.path().join("tree/sub/b.txt"),
+    )
+    .unwrap();
+
+    let mut builder = ArchiveBuilder::new();
+    builder
+        .add_path_as(source.path().join("tree"), "tree")
+        .unwrap();
+    let mut data = Vec::new();
+    builder.write(&mut data).unwrap();
+
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+    let toc = archive.toc();
+    let original = toc.lookup("tree/a.txt").unwrap();
+    let link = toc.lookup("tree/sub/b.txt").unwrap();
+    let (original_id, link_id) = (original.entry().unwrap().id, link.entry().unwrap().id);
+    assert_eq!(original.entry().unwrap().hardlink, Some(Hardlink::Original));
+    assert!(link.entry().unwrap().data.is_none());
+    assert_eq!(link.data().unwrap(), original.data().unwrap());
+    assert_eq!(
+        toc.hardlinks().unwrap().get(&original_id.unwrap()),
+        Some(&vec![link_id.unwrap()])
+    );
+
+    // reading and listing the link goes through to the original.
+    let mut raw = Vec::new();
+    archive
+        .open_data(&link)
+        .unwrap()
+        .read_to_end(&mut raw)
+        .unwrap();
+    let raw = Encoding::Gzip.decoder(&raw[..]).unwrap();
+    assert_eq!(std::io::read_to_string(raw).unwrap(), "shared");
+    let listed = Listed::list(toc.files().unwrap(), true).unwrap();
+    let row = listed
+        .iter()
+        .find(|listed| listed.path == "tree/sub/b.txt")
+        .unwrap()
+        .long_row();
+    assert!(row.contains(" 6 "), "{}", row);
+
+    let dest = tempfile::tempdir().unwrap();
+    archive.extract_all(dest.path()).unwrap();
+    let a = fs::metadata(dest.path().join("tree/a.txt")).unwrap();
+    let b = fs::metadata(dest.path().join("tree/sub/b.txt")).unwrap();
+    assert_eq!(a.ino(), b.ino());
+    assert_eq!(
+        fs::read_to_string(dest.path().join("tree/sub/b.txt")).unwrap(),
+        "shared"
+    );
+
+    // the link gets the data even when its original is not extracted.
+    let dest = tempfile::tempdir().unwrap();
+    archive.extract(dest.path(), &["tree/sub"]).unwrap();
+    assert_eq!(
+        fs::read_to_string(dest.path().join("tree/sub/b.txt")).unwrap(),
+        "shared"
+    );
+    assert!(!dest.path().join("tree/a.txt").exists());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_xattrs_roundtrip() {
+    let source = tempfile::tempdir().unwrap();
+    let file = source.path().join("tagged.txt");
+    fs::write(&file, "data").unwrap();
+    match xattr::set(&file, "user.comment", b"hello xattr") {
+        // the file system of the temporary directory might not support them.
+        Err(ref e) if xattr::is_unsupported(e) => return,
+        result => result.unwrap(),
+    }
+
+    let mut builder = ArchiveBuilder::new();
+    builder.add_path_as(&file, "tagged.txt").unwrap();
+    let mut data = Vec::new();
+    builder.write(&mut data).unwrap();
+
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+    let xattrs = archive
+        .toc()
+        .lookup("tagged.txt")
+        .unwrap()
+        .xattrs()
+        .unwrap();
+    assert_eq!(xattrs.len(), 1);
+    assert_eq!(xattrs[0].name, "user.comment");
+    let mut value = Vec::new();
+    archive
+        .open_xattr(&xattrs[0])
+        .unwrap()
+        .read_to_end(&mut value)
+        .unwrap();
+    assert_eq!(value, b"hello xattr");
+
+    let dest = tempfile::tempdir().unwrap();
+    archive.extract_all(dest.path()).unwrap();
+    let extracted = xattr::list(&dest.path().join("tagged.txt")).unwrap();
+    assert_eq!(
+        extracted,
+        vec![("user.comment".to_string(), b"hello xattr".to_vec())]
+    );
+
+    let dest = tempfile::tempdir().unwrap();
+    Extractor::new(&archive, dest.path())
+        .xattrs(false)
+        .extract()
+        .unwrap();
+    assert!(xattr::list(&dest.path().join("tagged.txt"))
+        .unwrap()
+        .is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_extract_applies_metadata() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    archive.extract_all(dest.path()).unwrap();
+
+    // 2019-06-08T10:21:03Z
+    let mtime = 1559989263;
+    for path in &["dir", "dir/hello.txt", "dir/link", "empty"] {
+        let metadata = fs::symlink_metadata(dest.path().join(path)).unwrap();
+        assert_eq!(metadata.mtime(), mtime, "{}", path);
+        assert_eq!(metadata.atime(), mtime, "{}", path);
+    }
+
+    let hello = fs::metadata(dest.path().join("dir/hello.txt")).unwrap();
+    assert_eq!(hello.permissions().mode() & 0o7777, 0o644 & !umask());
+
+    let dest = tempfile::tempdir().unwrap();
+    let is_root = unsafe { libc::geteuid() } == 0;
+    Extractor::new(&archive, dest.path())
+        .preserve_permissions(true)
+        .same_owner(is_root)
+        .numeric_owner(true)
+        .extract()
+        .unwrap();
+    let dir = fs::metadata(dest.path().join("dir")).unwrap();
+    assert_eq!(dir.permissions().mode() & 0o7777, 0o755);
+    if is_root {
+        let hello = fs::metadata(dest.path().join("dir/hello.txt")).unwrap();
+        assert_eq!((hello.uid(), hello.gid()), (501, 20));
+    }
+}
+
+#[cfg(unix)]
+fn umask() -> u32 {
+    let umask = unsafe { libc::umask(0o022) };
+    unsafe { libc::umask(umask) };
+    umask as u32
+}
+
+/// Archive without checksums, with the given toc and heap.
+fn archive_from_toc(toc: &str, heap: &[u8]) -> Vec<u8> {
+    let toc = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><xar><toc><checksum style="none"/>{}</toc></xar>"#,
+        toc
+    );
+    let compressed = Encoding::Gzip.encode(toc.as_bytes()).unwrap();
+    let header = Header::new(
+        compressed.len() as u64,
+        toc.len() as u64,
+        header::ChecksumAlg::None,
+    );
+
+    let mut data = Vec::new();
+    header.write(&mut data).unwrap();
+    data.extend_from_slice(&compressed);
+    data.extend_from_slice(heap);
+    data
+}
+
+#[test]
+fn test_extract_rejects_unsafe_names() {
+    let toc = |name: &str| {
+        format!(
+            "<file id=\"1\"><type>file</type><name>{}</name></file>",
+            name
+        )
+    };
+
+    // a NUL can't even be written in xml.
+    let data = archive_from_toc(&toc("a&#0;b"), b"");
+    assert!(matches!(
+        Archive::from_read(Cursor::new(&data)),
+        Err(Error::Xml(_))
+    ));
+
+    for name in &[
+        "..",
+        ".",
+        "../evil",
+        "/etc/evil",
+        "a/b",
+        "a\\b",
+        "&lt;a&gt;/&amp;",
+    ] {
+        let data = archive_from_toc(&toc(name), b"");
+        let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        let error = archive.extract_all(dest.path()).unwrap_err();
+        assert!(
+            matches!(error, Error::Extract(extract::Errors::UnsafePath(_))),
+            "{:?}: {}",
+            name,
+            error
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_extract_rejects_symlink_escapes() {
+    let outside = tempfile::tempdir().unwrap();
+    let toc = format!(
+        r#"<file id="1"><type>symlink</type><link type="directory">{}</link><name>link</name></file>
+        <file id="2"><type>directory</type><name>link</name>
+            <file id="3"><type>file</type><name>evil</name></file>
+        </file>"#,
+        outside.path().display()
+    );
+    let data = archive_from_toc(&toc, b"");
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+
+    let dest = tempfile::tempdir().unwrap();
+    let error = archive.extract_all(dest.path()).unwrap_err();
+    assert!(matches!(
+        error,
+        Error::Extract(extract::Errors::SymlinkEscape(_))
+    ));
+    assert!(!outside.path().join("evil").exists());
+
+    let dest = tempfile::tempdir().unwrap();
+    Extractor::new(&archive, dest.path())
+        .unsafe_paths(true)
+        .extract()
+        .unwrap();
+    assert!(outside.path().join("evil").exists());
+}
+
+#[test]
+fn test_read_limits_toc() {
+    let read = |limits: ReadLimits| Archive::from_read_with_limits(Cursor::new(FILES_XAR), limits);
+    let error = |limits: ReadLimits| match read(limits).unwrap_err() {
+        Error::Limits(error) => error,
+        error => panic!("{}", error),
+    };
+
+    assert!(read(ReadLimits::default()).is_ok());
+    assert!(read(ReadLimits::unlimited()).is_ok());
+    assert!(matches!(
+        error(ReadLimits {
+            max_header_size: 27,
+            ..ReadLimits::default()
+        }),
+        limits::Errors::HeaderTooLarge(28, 27)
+    ));
+    assert!(matches!(
+        error(ReadLimits {
+            max_toc_size: 100,
+            ..ReadLimits::default()
+        }),
+        limits::Errors::TocTooLarge(100)
+    ));
+    assert!(matches!(
+        error(ReadLimits {
+            max_files: 4,
+            ..ReadLimits::default()
+        }),
+        limits::Errors::TooManyFiles(4)
+    ));
+    assert!(matches!(
+        error(ReadLimits {
+            max_depth: 1,
+            ..ReadLimits::default()
+        }),
+        limits::Errors::TooDeep(1)
+    ));
+}
+
+#[test]
+fn test_read_limits_extraction() {
+    let extract = |limits: ReadLimits| {
+        let archive = Archive::from_read_with_limits(Cursor::new(FILES_XAR), limits).unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        match archive.extract_all(dest.path()).unwrap_err() {
+            Error::Limits(error) => error,
+            error => panic!("{}", error),
+        }
+    };
+
+    // lorem.txt is 1140 bytes, gzipped to 74.
+    assert!(matches!(
+        extract(ReadLimits {
+            max_file_size: 1000,
+            ..ReadLimits::default()
+        }),
+        limits::Errors::FileTooLarge(ref path, 1000) if path == "dir/lorem.txt"
+    ));
+    assert!(matches!(
+        extract(ReadLimits {
+            max_ratio: 10,
+            ..ReadLimits::default()
+        }),
+        limits::Errors::RatioTooHigh(ref path, 10) if path == "dir/lorem.txt"
+    ));
+    assert!(matches!(
+        extract(ReadLimits {
+            max_total_size: 1100,
+            ..ReadLimits::default()
+        }),
+        limits::Errors::TotalTooLarge(1100)
+    ));
+}
+
+#[test]
+#[cfg(feature = "bzip2")]
+fn test_read_limits_default_ratio() {
+    // 16 MiB of zeros compress to a few dozen bytes.
+    let mut builder = ArchiveBuilder::new();
+    builder.encoding(Encoding::Bzip2);
+    builder.add_data("bomb", vec![0; 16 * 1024 * 1024]).unwrap();
+    let mut data = Vec::new();
+    builder.write(&mut data).unwrap();
+
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    assert!(matches!(
+        archive.extract_all(dest.path()),
+        Err(Error::Limits(limits::Errors::RatioTooHigh(ref path, 10_000))) if path == "bomb"
+    ));
+}
+
+#[test]
+fn test_toc_lengths_enforced() {
+    use byteorder::{BigEndian, ByteOrder};
+
+    let error = |offset: usize, change: i64| {
+        let mut copy: Vec<u8> = FILES_XAR.into();
+        let length = BigEndian::read_u64(&copy[offset..offset + 8]);
+        BigEndian::write_u64(
+            &mut copy[offset..offset + 8],
+            (length as i64 + change) as u64,
+        );
+        match Archive::from_read(Cursor::new(&copy)).unwrap_err() {
+            Error::Toc(error) => error,
+            error => panic!("{}", error),
+        }
+    };
+
+    let compressed = Header::from_read(&mut Cursor::new(FILES_XAR))
+        .unwrap()
+        .toc_length_compressed;
+    assert!(matches!(
+        error(8, 1),
+        Errors::CompressedLengthMismatch(expected, actual)
+            if expected == compressed + 1 && actual == compressed
+    ));
+    assert!(matches!(error(8, -1), Errors::Truncated));
+    assert!(matches!(
+        error(16, 1),
+        Errors::UncompressedLengthMismatch(..)
+    ));
+    assert!(matches!(
+        error(16, -1),
+        Errors::UncompressedLengthMismatch(..)
+    ));
+}
+
+#[test]
+fn test_error_source() {
+    use std::error::Error as _;
+
+    let data = archive_from_toc("<file>", b"");
+    let error = Archive::from_read(Cursor::new(&data)).unwrap_err();
+    assert!(matches!(error, Error::Xml(_)));
+    assert!(error.source().unwrap().is::<xmltree::ParseError>());
+
+    let dest = tempfile::tempdir().unwrap();
+    let data = archive_from_toc(r#"<file id="1"><type>file</type></file>"#, b"");
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+    let error = archive.extract_all(dest.path()).unwrap_err();
+    assert!(matches!(error, Error::Extract(extract::Errors::NoName(_))));
+    assert!(error.source().unwrap().is::<extract::Errors>());
+}
+
+#[test]
+fn test_extract_stream() {
+    // a slice can be read but not seeked.
+    let archive = Archive::from_read(FILES_XAR).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    Extractor::new(&archive, dest.path())
+        .extract_stream()
+        .unwrap();
+
+    let dir = dest.path().join("dir");
+    assert_eq!(
+        fs::read_to_string(dir.join("hello.txt")).unwrap(),
+        "Hello, world!\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("lorem.txt")).unwrap(),
+        "Lorem ipsum dolor sit amet, consectetur adipiscing elit.\n".repeat(20)
+    );
+    assert_eq!(
+        fs::read_link(dir.join("link")).unwrap(),
+        Path::new("hello.txt")
+    );
+    assert_eq!(fs::read(dest.path().join("empty")).unwrap().len(), 0);
+}
+
+#[test]
+fn test_extract_paths() {
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    Extractor::new(&archive, dest.path())
+        .paths(&["dir/hello.txt"])
+        .extract()
+        .unwrap();
+    assert!(dest.path().join("dir/hello.txt").exists());
+    assert!(!dest.path().join("dir/lorem.txt").exists());
+    assert!(!dest.path().join("empty").exists());
+
+    // entries leading up to a path don't count as finding it.
+    let dest = tempfile::tempdir().unwrap();
+    let error = Extractor::new(&archive, dest.path())
+        .paths(&["dir", "dir/hello.txt/nope", "nope"])
+        .extract()
+        .unwrap_err();
+    match error {
+        Error::Extract(extract::Errors::NotFound(missing)) => {
+            assert_eq!(missing, vec!["dir/hello.txt/nope", "nope"])
+        }
+        error => panic!("unexpected error {:?}", error),
+    }
+    assert!(dest.path().join("dir/lorem.txt").exists());
+}
+
+#[test]
+fn test_extract_truncated() {
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    let lorem = archive.toc().lookup("dir/lorem.txt").unwrap();
+    let data = lorem.data().unwrap().unwrap();
+
+    // cut off in the middle of the data of lorem.txt.
+    let end = archive.heap_offset() + data.offset + data.length / 2;
+    let truncated = Archive::from_read(Cursor::new(&FILES_XAR[..end as usize])).unwrap();
+    let mut raw = Vec::new();
+    let error = truncated
+        .open_heap(data.offset, data.length)
+        .unwrap()
+        .read_to_end(&mut raw)
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+
+    let dest = tempfile::tempdir().unwrap();
+    assert!(matches!(
+        truncated.extract_all(dest.path()),
+        Err(Error::Io(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+    ));
+}
+
+#[test]
+fn test_extract_stream_shared_and_overlapping_data() {
+    let file = |id: u32, name: &str, offset: u64, length: u64| {
+        format!(
+            r#"<file id="{}"><type>file</type><name>{}</name><data>
+                <length>{}</length><offset>{}</offset><size>{}</size>
+                <encoding style="application/octet-stream"/>
+            </data></file>"#,
+            id, name, length, offset, length
+        )
+    };
+
+    // listed out of heap order, with two files sharing their data.
+    let toc = [
+        file(1, "c", 10, 3),
+        file(2, "a", 0, 5),
+        file(3, "b", 0, 5),
+        file(4, "d", 5, 2),
+    ]
+    .concat();
+    let data = archive_from_toc(&toc, b"hello  ...xyz");
+    let archive = Archive::from_read(&data[..]).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    Extractor::new(&archive, dest.path())
+        .extract_stream()
+        .unwrap();
+    let read = |name: &str| fs::read_to_string(dest.path().join(name)).unwrap();
+    assert_eq!(read("a"), "hello");
+    assert_eq!(read("b"), "hello");
+    assert_eq!(read("c"), "xyz");
+    assert_eq!(read("d"), "  ");
+
+    let toc = [file(1, "a", 0, 5), file(2, "b", 3, 5)].concat();
+    let data = archive_from_toc(&toc, b"hello world");
+    let archive = Archive::from_read(&data[..]).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let error = Extractor::new(&archive, dest.path())
+        .extract_stream()
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        Error::Extract(extract::Errors::DataOutOfOrder(ref path)) if path == "b"
+    ));
+}
+
+#[test]
+#[cfg(all(feature = "mmap", feature = "bzip2", feature = "xz"))]
+fn test_open_mmap() {
+    use crate::archive::Data;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("encodings.xar");
+    fs::write(&path, ENCODINGS_XAR).unwrap();
+    let archive = Archive::open_mmap(&path).unwrap();
+    assert_eq!(
+        archive.heap().len() as u64,
+        ENCODINGS_XAR.len() as u64 - archive.heap_offset()
+    );
+
+    let expected = "The quick brown fox jumps over the lazy dog.\n".repeat(10);
+    let none = archive.toc().lookup("none.txt").unwrap();
+    match archive.data(&none).unwrap() {
+        Data::Slice(slice) => assert_eq!(slice, expected.as_bytes()),
+        Data::Decoder(_) => panic!("data without encoding is decoded"),
+    }
+
+    let gzip = archive.toc().lookup("gzip.txt").unwrap();
+    let mut data = archive.data(&gzip).unwrap();
+    assert!(matches!(data, Data::Decoder(_)));
+    let mut decoded = String::new();
+    data.read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, expected);
+
+    // slices can be held while the archive is read otherwise.
+    let raw = archive.heap_slice(0, 4).unwrap();
+    archive.extract_all(dir.path()).unwrap();
+    assert_eq!(raw, &archive.heap()[..4]);
+
+    let length = archive.heap().len() as u64;
+    assert!(matches!(
+        archive.heap_slice(length - 1, 2),
+        Err(Error::Archive(crate::archive::Errors::HeapOutOfBounds(
+            _,
+            2
+        )))
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_extract_jobs() {
+    use std::os::unix::ffi::OsStringExt;
+    use std::os::unix::fs::MetadataExt;
+
+    // path, contents or link target, mode and mtime of everything below root.
+    fn walk(root: &Path, dir: &Path, found: &mut Vec<(String, Vec<u8>, u32, i64)>) {
+        let mut entries: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap()).collect();
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let metadata = fs::symlink_metadata(&path).unwrap();
+            let contents = if metadata.file_type().is_symlink() {
+                fs::read_link(&path).unwrap().into_os_string().into_vec()
+            } else if metadata.is_file() {
+                fs::read(&path).unwrap()
+            } else {
+                walk(root, &path, found);
+                Vec::new()
+            };
+            let name = path.strip_prefix(root).unwrap().display().to_string();
+            found.push((name, contents, metadata.mode(), metadata.mtime()));
+        }
+    }
+
+    // from disk, so that every entry has an mtime to compare.
+    let source = tempfile::tempdir().unwrap();
+    for i in 0..50 {
+        let content = format!("file number {}\n", i).repeat(i);
+        fs::write(source.path().join(format!("file{}", i)), content).unwrap();
+    }
+    let mut builder = ArchiveBuilder::new();
+    builder.add_path_as(source.path(), "tree").unwrap();
+    let mut built = Vec::new();
+    builder.write(&mut built).unwrap();
+
+    for data in &[FILES_XAR, &built[..]] {
+        let archive = Archive::from_read(Cursor::new(data)).unwrap();
+        let serial = tempfile::tempdir().unwrap();
+        archive.extract_all(serial.path()).unwrap();
+        let mut expected = Vec::new();
+        walk(serial.path(), serial.path(), &mut expected);
+
+        // each worker reads through a cursor of its own.
+        for jobs in &[1, 2, 8] {
+            let parallel = tempfile::tempdir().unwrap();
+            Extractor::new(&archive, parallel.path())
+                .jobs(*jobs)
+                .reopen_with(|| Ok(Cursor::new(data)))
+                .extract()
+                .unwrap();
+            let mut found = Vec::new();
+            walk(parallel.path(), parallel.path(), &mut found);
+            assert_eq!(found, expected);
+        }
+
+        #[cfg(feature = "mmap")]
+        {
+            let mapped = tempfile::NamedTempFile::new().unwrap();
+            fs::write(mapped.path(), data).unwrap();
+            let archive = Archive::open_mmap(mapped.path()).unwrap();
+            let parallel = tempfile::tempdir().unwrap();
+            Extractor::new(&archive, parallel.path())
+                .jobs(4)
+                .extract()
+                .unwrap();
+            let mut found = Vec::new();
+            walk(parallel.path(), parallel.path(), &mut found);
+            assert_eq!(found, expected);
+        }
+    }
+
+    let archive = Archive::from_read(FILES_XAR).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    Extractor::new(&archive, dest.path())
+        .jobs(4)
+        .extract_stream()
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(dest.path().join("dir/hello.txt")).unwrap(),
+        "Hello, world!\n"
+    );
+
+    // limits hold across threads.
+    let limits = ReadLimits {
+        max_total_size: 1100,
+        ..ReadLimits::default()
+    };
+    let archive = Archive::from_read_with_limits(Cursor::new(FILES_XAR), limits).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let error = Extractor::new(&archive, dest.path())
+        .jobs(4)
+        .reopen_with(|| Ok(Cursor::new(FILES_XAR)))
+        .extract()
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        Error::Limits(limits::Errors::TotalTooLarge(1100))
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_builder_jobs() {
+    let source = tempfile::tempdir().unwrap();
+    fs::create_dir_all(source.path().join("tree/sub")).unwrap();
+    for i in 0..30 {
+        let content = format!("file number {}\n", i).repeat(i * 10);
+        fs::write(source.path().join(format!("tree/sub/{}.txt", i)), content).unwrap();
+    }
+    fs::hard_link(
+        source.path().join("tree/sub/7.txt"),
+        source.path().join("tree/link.txt"),
+    )
+    .unwrap();
+    // more than worker threads keep in memory, even compressed.
+    let mut state = 1u32;
+    let noise: Vec<u8> = (0..3 << 19)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        })
+        .collect();
+    fs::write(source.path().join("tree/noise.bin"), &noise).unwrap();
+
+    // all are added before any is written, as reading files changes their
+    // access time.
+    let builders: Vec<_> = [1, 2, 4, 16]
+        .iter()
+        .map(|jobs| {
+            let mut builder = ArchiveBuilder::new();
+            builder.jobs(*jobs);
+            builder
+                .add_path_as(source.path().join("tree"), "tree")
+                .unwrap();
+            builder
+                .add_data("memory.txt", b"in memory".to_vec())
+                .unwrap();
+            builder
+        })
+        .collect();
+
+    // everything but the creation time, which changes with every write, and
+    // the toc checksum at the start of the heap, which covers it.
+    let written: Vec<_> = builders
+        .iter()
+        .map(|builder| {
+            let mut data = Vec::new();
+            builder.write(&mut data).unwrap();
+            let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+            assert!(archive.verify_files().unwrap().is_empty());
+
+            let mut toc = archive.toc().data().clone();
+            toc.get_mut_child("toc")
+                .unwrap()
+                .children
+                .retain(|child| child.name != "creation-time");
+            let checksum_size = archive.toc().checksum_size().unwrap();
+            let heap = data[archive.heap_offset() as usize + checksum_size..].to_vec();
+            (toc, heap)
+        })
+        .collect();
+    for other in &written[1..] {
+        assert!(*other == written[0]);
+    }
+
+    // a file that went missing is reported, whichever thread reads it.
+    let mut builder = ArchiveBuilder::new();
+    builder.jobs(4);
+    builder
+        .add_path_as(source.path().join("tree"), "tree")
+        .unwrap();
+    fs::remove_file(source.path().join("tree/sub/3.txt")).unwrap();
+    assert!(matches!(builder.write(&mut Vec::new()), Err(Error::Io(_))));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_builder_reproducible() {
+    // the same tree, created twice with different inodes and times.
+    let make_tree = || {
+        let source = tempfile::tempdir().unwrap();
+        let tree = source.path().join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        for name in &["b.txt", "a.txt", "sub/c.txt"] {
+            fs::write(tree.join(name), name.repeat(100)).unwrap();
+        }
+        fs::hard_link(tree.join("a.txt"), tree.join("sub/link.txt")).unwrap();
+        source
+    };
+
+    let epoch = 1_000_000_000;
+    let write = |source: &Path, jobs: usize, reversed: bool| {
+        let mut builder = ArchiveBuilder::new();
+        builder.reproducible(epoch).unwrap().jobs(jobs);
+        let mut names = vec!["x.txt", "y.txt"];
+        if reversed {
+            names.reverse();
+        }
+        for name in names {
+            builder.add_data(name, name.as_bytes().to_vec()).unwrap();
+        }
+        builder.add_path_as(source.join("tree"), "tree").unwrap();
+        let mut data = Vec::new();
+        builder.write(&mut data).unwrap();
+        data
+    };
+
+    let first = make_tree();
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let second = make_tree();
+    let data = write(first.path(), 1, false);
+    assert!(data == write(second.path(), 4, true));
+
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+    assert_eq!(archive.toc().creation_time().unwrap().timestamp(), epoch);
+    let entry = archive.toc().lookup("tree/a.txt").unwrap().entry().unwrap();
+    assert_eq!(entry.mtime.unwrap().timestamp(), epoch);
+    assert_eq!((entry.uid, entry.gid), (Some(0), Some(0)));
+    assert_eq!((entry.inode, entry.deviceno), (None, None));
+    assert_eq!(entry.hardlink, Some(Hardlink::Original));
+    let names: Vec<_> = archive
+        .toc()
+        .files()
+        .unwrap()
+        .iter()
+        .map(|file| file.name().unwrap())
+        .collect();
+    assert_eq!(names, ["tree", "x.txt", "y.txt"]);
+
+    // an epoch in the future is the creation time as it is.
+    let epoch = 4_000_000_000;
+    let mut builder = ArchiveBuilder::new();
+    builder.reproducible(epoch).unwrap();
+    let mut data = Vec::new();
+    builder.write(&mut data).unwrap();
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+    assert_eq!(archive.toc().creation_time().unwrap().timestamp(), epoch);
+
+    // chrono can't go this far.
+    assert!(matches!(
+        ArchiveBuilder::new().reproducible(i64::MAX),
+        Err(Error::Builder(builder::Errors::TimestampOutOfRange(
+            i64::MAX
+        )))
+    ));
+}
+
+#[test]
+fn test_append() {
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    let mut builder = ArchiveBuilder::new();
+    builder.add_data("dir/new.txt", b"new".to_vec()).unwrap();
+    builder.add_data("top.txt", b"top".to_vec()).unwrap();
+    let mut data = Vec::new();
+    archive.append(&builder, &mut data).unwrap();
+
+    let appended = Archive::from_read(Cursor::new(&data)).unwrap();
+    appended.verify_toc_checksum().unwrap();
+    assert!(appended.verify_files().unwrap().is_empty());
+
+    // existing data stays where it was, only the toc checksum changes.
+    let old_heap = &FILES_XAR[archive.heap_offset() as usize..];
+    let new_heap = &data[appended.heap_offset() as usize..];
+    let checksum_size = archive.toc().checksum_size().unwrap();
+    assert_eq!(
+        new_heap[checksum_size..old_heap.len()],
+        old_heap[checksum_size..]
+    );
+    let hello = appended.toc().lookup("dir/hello.txt").unwrap();
+    assert_eq!(
+        hello.entry().unwrap().data,
+        archive
+            .toc()
+            .lookup("dir/hello.txt")
+            .unwrap()
+            .entry()
+            .unwrap()
+            .data
+    );
+
+    let mut ids: Vec<_> = ["dir", "dir/hello.txt", "dir/new.txt", "top.txt", "empty"]
+        .iter()
+        .map(|path| appended.toc().lookup(path).unwrap().entry().unwrap().id)
+        .collect();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 5);
+
+    let dest = tempfile::tempdir().unwrap();
+    appended.extract_all(dest.path()).unwrap();
+    let read = |path: &str| fs::read_to_string(dest.path().join(path)).unwrap();
+    assert_eq!(read("dir/hello.txt"), "Hello, world!\n");
+    assert_eq!(read("dir/new.txt"), "new");
+    assert_eq!(read("top.txt"), "top");
+
+    let mut builder = ArchiveBuilder::new();
+    builder
+        .add_data("dir/hello.txt", b"again".to_vec())
+        .unwrap();
+    assert!(matches!(
+        archive.append(&builder, &mut Vec::new()),
+        Err(Error::Archive(crate::archive::Errors::AlreadyExists(ref path)))
+            if path == "dir/hello.txt"
+    ));
+}
+
+#[test]
+fn test_rebuild() {
+    let archive = Archive::from_read(Cursor::new(FILES_XAR)).unwrap();
+    let mut data = Vec::new();
+    Rebuilder::new(&archive)
+        .encoding(Encoding::OctetStream)
+        .checksum_alg(header::ChecksumAlg::SHA256)
+        .write(&mut data)
+        .unwrap();
+
+    let rebuilt = Archive::from_read(Cursor::new(&data)).unwrap();
+    assert_eq!(rebuilt.header().checksum_alg, header::ChecksumAlg::SHA256);
+    rebuilt.verify_toc_checksum().unwrap();
+    assert!(rebuilt.verify_files().unwrap().is_empty());
+    let hello = rebuilt.toc().lookup("dir/hello.txt").unwrap();
+    let hello = hello.entry().unwrap().data.unwrap();
+    assert_eq!(hello.encoding, Encoding::OctetStream);
+    assert_eq!(
+        hello.extracted_checksum.unwrap().alg,
+        header::ChecksumAlg::SHA256
+    );
+
+    let dest = tempfile::tempdir().unwrap();
+    rebuilt.extract_all(dest.path()).unwrap();
+    assert_eq!(
+        fs::read_to_string(dest.path().join("dir/hello.txt")).unwrap(),
+        "Hello, world!\n"
+    );
+
+    // data is checked as it streams through.
+    let toc = r#"<file id="1">
+            <name>file.txt</name><type>file</type>
+            <data>
+                <length>5</length><offset>0</offset><size>5</size>
+                <encoding style="application/octet-stream"/>
+                <extracted-checksum style="sha1">aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d</extracted-checksum>
+            </data>
+        </file>"#;
+    let data = archive_from_toc(toc, b"hellx");
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+    assert!(matches!(
+        Rebuilder::new(&archive).write(&mut Vec::new()),
+        Err(Error::Rebuild(crate::rebuild::Errors::ChecksumMismatch(ref path))) if path == "file.txt"
+    ));
+}
+
+#[test]
+fn test_rebuild_compacts_heap() {
+    let mut builder = ArchiveBuilder::new();
+    builder.add_data("a.txt", b"same".to_vec()).unwrap();
+    builder.add_data("b.txt", b"same".to_vec()).unwrap();
+    let mut data = Vec::new();
+    builder.write(&mut data).unwrap();
+    // orphaned data at the end of the heap.
+    data.extend_from_slice(&[0; 64]);
+    let archive = Archive::from_read(Cursor::new(&data)).unwrap();
+
+    let rebuild = |deduplicate| {
+        let mut data = Vec::new();
+        Rebuilder::new(&archive)
+            .deduplicate(deduplicate)
+            .write(&mut data)
+            .unwrap();
+        data
+    };
+    let offset = |archive: &Archive<Cursor<&Vec<u8>>>, path| {
+        let file = archive.toc().lookup(path).unwrap();
+        file.entry().unwrap().data.unwrap().offset
+    };
+
+    let compacted = rebuild(false);
+    assert_eq!(compacted.len(), data.len() - 64);
+    let rebuilt = Archive::from_read(Cursor::new(&compacted)).unwrap();
+    assert_ne!(offset(&rebuilt, "a.txt"), offset(&rebuilt, "b.txt"));
+
+    let deduplicated = rebuild(true);
+    assert!(deduplicated.len() < compacted.len());
+    let rebuilt = Archive::from_read(Cursor::new(&deduplicated)).unwrap();
+    assert!(rebuilt.verify_files().unwrap().is_empty());
+    assert_eq!(offset(&rebuilt, "a.txt"), offset(&rebuilt, "b.txt"));
+
+    let dest = tempfile::tempdir().unwrap();
+    rebuilt.extract_all(dest.path()).unwrap();
+    assert_eq!(fs::read(dest.path().join("a.txt")).unwrap(), b"same");
+    assert_eq!(fs::read(dest.path().join("b.txt")).unwrap(), b"same");
+}
@@ -1,29 +1,55 @@
-use chrono::NaiveDateTime;
-use failure::*;
+// `serde_derive` expands to code that newer compilers lint against.
+#![allow(non_local_definitions)]
+
+use crate::encoding::Encoding;
+use crate::header::ChecksumAlg;
+use crate::limits::{self, ReadLimits};
+use crate::Error;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use libflate::zlib::Decoder;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
 use std::fmt;
 use std::io::{Read, Write};
 use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
 use xmltree::Element;
 
-#[derive(Fail, Debug)]
+#[derive(Error, Debug)]
 pub enum Errors {
-    #[fail(display = "<toc> element doesn't exist in Toc.")]
+    #[error("<toc> element doesn't exist in Toc.")]
     NoTocElement,
-    #[fail(display = "<creation-time> element doesn't exist in Toc.")]
+    #[error("<creation-time> element doesn't exist in Toc.")]
     NoCreationTime,
-    #[fail(display = "<checksum> element missing.")]
+    #[error("<checksum> element missing.")]
     NoChecksumElement,
-    #[fail(display = "style attribute in <checksum> element missing.")]
+    #[error("<creation-time> element of <toc> is invalid.")]
+    CreationTimeInvalid,
+    #[error("style attribute in <checksum> element missing.")]
     NoChecksumType,
-    #[fail(display = "style attribute in <checksum> element missing.")]
-    NoFileTypeElement,
-    #[fail(display = "style attribute in <checksum> element missing.")]
-    NoFileNameElement,
-    #[fail(display = "style attribute in <checksum> element missing.")]
-    NoFileId,
-    #[fail(display = "style attribute in <checksum> element missing.")]
+    #[error("<offset> element of <checksum> is missing or invalid.")]
     ChecksumOffsetInvalid,
+    #[error("<size> element of <checksum> is missing or invalid.")]
+    ChecksumSizeInvalid,
+    #[error("<{0}> element of <file> is invalid.")]
+    FileElementInvalid(&'static str),
+    #[error("<{0}> element of <data> is missing or invalid.")]
+    FileDataElementInvalid(&'static str),
+    #[error("Unknown file type ‘{0}’.")]
+    UnknownFileType(String),
+    #[error("Invalid file id ‘{0}’.")]
+    FileIdInvalid(String),
+    #[error("Hardlink refers to missing file {0}.")]
+    HardlinkOriginalMissing(u64),
+    #[error("Toc is {1} bytes compressed, header says {0}.")]
+    CompressedLengthMismatch(u64, u64),
+    #[error("Toc is {1} bytes uncompressed, header says {0}.")]
+    UncompressedLengthMismatch(u64, u64),
+    #[error("Toc ends before its compressed data does.")]
+    Truncated,
 }
 
 /// Table of contents.
@@ -33,16 +59,73 @@ pub struct Toc {
 }
 
 impl Toc {
-    /// Contstruct a toc from a reader pointed at the start of it.
-    pub fn from_read<T: Read>(reader: &mut T, _expected: usize) -> Result<Toc, Error> {
-        // TODO: check expected toc size.
+    /// Read a toc that inflates to `expected` bytes. The reader should end
+    /// where the compressed toc does.
+    pub fn from_read<T: Read>(reader: &mut T, expected: usize) -> Result<Toc, Error> {
+        Self::from_read_with_limits(reader, expected, &ReadLimits::default())
+    }
 
-        let mut decoder = Decoder::new(reader)?;
-        let element = Element::parse(&mut decoder)?;
+    /// Read a toc, refusing to decompress more than the limits allow or
+    /// to hold more or deeper nested files.
+    pub fn from_read_with_limits<T: Read>(
+        reader: &mut T,
+        expected: usize,
+        limits: &ReadLimits,
+    ) -> Result<Toc, Error> {
+        let expected = expected as u64;
+        if expected > limits.max_toc_size {
+            return Err(limits::Errors::TocTooLarge(limits.max_toc_size).into());
+        }
+
+        let decoder = Decoder::new(reader).map_err(Self::truncated)?;
+        let mut data = Vec::new();
+        decoder
+            .take(expected.saturating_add(1))
+            .read_to_end(&mut data)
+            .map_err(Self::truncated)?;
+        if data.len() as u64 != expected {
+            return Err(Errors::UncompressedLengthMismatch(expected, data.len() as u64).into());
+        }
+
+        let element = Element::parse(&data[..])?;
+        Self::check_limits(&element, limits)?;
 
         Ok(Toc { data: element })
     }
 
+    fn truncated(error: std::io::Error) -> Error {
+        match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => Errors::Truncated.into(),
+            _ => Error::Zlib(error),
+        }
+    }
+
+    /// Count files and their nesting, without recursing so that deep
+    /// nesting can't overflow the stack.
+    fn check_limits(element: &Element, limits: &ReadLimits) -> Result<(), limits::Errors> {
+        let mut count = 0;
+        let mut stack = vec![(element, 0)];
+        while let Some((element, depth)) = stack.pop() {
+            for child in &element.children {
+                if child.name != "file" {
+                    stack.push((child, depth));
+                    continue;
+                }
+
+                count += 1;
+                if count > limits.max_files {
+                    return Err(limits::Errors::TooManyFiles(limits.max_files));
+                }
+                if depth + 1 > limits.max_depth {
+                    return Err(limits::Errors::TooDeep(limits.max_depth));
+                }
+                stack.push((child, depth + 1));
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn data(&self) -> &Element {
         &self.data
     }
@@ -53,11 +136,11 @@ impl Toc {
     }
 
     /// Compute creation time of Toc.
-    pub fn creation_time(&self) -> Result<NaiveDateTime, Error> {
+    pub fn creation_time(&self) -> Result<NaiveDateTime, Errors> {
         let time = self.creation_time_element()?;
         let text = time.text.as_ref().ok_or(Errors::NoCreationTime)?;
-        let parsed = NaiveDateTime::parse_from_str(&text, "%Y-%m-%dT%H:%M:%S")?;
-        Ok(parsed)
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S")
+            .map_err(|_| Errors::CreationTimeInvalid)
     }
 
     fn creation_time_element(&self) -> Result<&Element, Errors> {
@@ -75,29 +158,21 @@ impl Toc {
     }
 
     /// Find out at which offset the checksum is.
-    pub fn checksum_offset(&self) -> Result<usize, Error> {
-        let re = self
-            .checksum_element()?
+    pub fn checksum_offset(&self) -> Result<usize, Errors> {
+        self.checksum_element()?
             .get_child("offset")
-            .ok_or(Errors::ChecksumOffsetInvalid)?
-            .text
-            .as_ref()
-            .ok_or(Errors::ChecksumOffsetInvalid)?
-            .parse::<usize>()?;
-        Ok(re)
+            .and_then(|offset| offset.text.as_ref())
+            .and_then(|offset| offset.parse::<usize>().ok())
+            .ok_or(Errors::ChecksumOffsetInvalid)
     }
 
     /// Find out how many bytes the checksum is.
-    pub fn checksum_size(&self) -> Result<usize, Error> {
-        let re = self
-            .checksum_element()?
+    pub fn checksum_size(&self) -> Result<usize, Errors> {
+        self.checksum_element()?
             .get_child("size")
-            .ok_or(Errors::ChecksumOffsetInvalid)?
-            .text
-            .as_ref()
-            .ok_or(Errors::ChecksumOffsetInvalid)?
-            .parse::<usize>()?;
-        Ok(re)
+            .and_then(|size| size.text.as_ref())
+            .and_then(|size| size.parse::<usize>().ok())
+            .ok_or(Errors::ChecksumSizeInvalid)
     }
 
     fn checksum_element(&self) -> Result<&Element, Errors> {
@@ -110,20 +185,58 @@ impl Toc {
         self.data.get_child("toc").ok_or(Errors::NoTocElement)
     }
 
-    pub fn files(&self) -> Result<Files, Errors> {
+    pub fn files(&self) -> Result<Files<'_>, Errors> {
+        let toc = self.toc_element()?;
         Ok(Files {
-            data: self.toc_element()?,
+            root: toc,
+            data: toc,
             path: PathBuf::new(),
         })
     }
+
+    /// Find a file by its path in the archive.
+    pub fn lookup<P: AsRef<Path>>(&self, path: P) -> Option<File<'_, 'static>> {
+        self.files().ok()?.find(path.as_ref())
+    }
+
+    /// Find a file by the id attribute of its `<file>` element.
+    pub fn file_by_id(&self, id: u64) -> Option<File<'_, 'static>> {
+        self.files().ok()?.find_id(id)
+    }
+
+    /// Map from the id of every original hardlink to the ids of the links
+    /// sharing its data.
+    pub fn hardlinks(&self) -> Result<BTreeMap<u64, Vec<u64>>, Errors> {
+        let mut graph = BTreeMap::new();
+        Self::collect_hardlinks(&self.files()?, &mut graph)?;
+        Ok(graph)
+    }
+
+    fn collect_hardlinks(files: &Files, graph: &mut BTreeMap<u64, Vec<u64>>) -> Result<(), Errors> {
+        for file in files.iter() {
+            let entry = file.entry()?;
+            match (entry.hardlink, entry.id) {
+                (Some(Hardlink::Original), Some(id)) => {
+                    graph.entry(id).or_default();
+                }
+                (Some(Hardlink::Of(original)), Some(id)) => {
+                    graph.entry(original).or_default().push(id);
+                }
+                _ => {}
+            }
+            Self::collect_hardlinks(&file.files(), graph)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl std::fmt::Display for Toc {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "creation-time {:?}\n", self.creation_time())?;
-        write!(f, "checksum-kind {:?}\n", self.checksum_type())?;
-        write!(f, "checksum-offset {:?}\n", self.checksum_offset())?;
-        write!(f, "checksum-size {:?}\n", self.checksum_size())
+        writeln!(f, "creation-time {:?}", self.creation_time())?;
+        writeln!(f, "checksum-kind {:?}", self.checksum_type())?;
+        writeln!(f, "checksum-offset {:?}", self.checksum_offset())?;
+        writeln!(f, "checksum-size {:?}", self.checksum_size())
     }
 }
 
@@ -142,6 +255,10 @@ pub enum FileElement {
     Type,
     Name,
     DeviceNo,
+    Link,
+    Device,
+    Flags,
+    Ea,
 }
 
 impl FileElement {
@@ -161,6 +278,10 @@ impl FileElement {
             Type => "type",
             Name => "name",
             DeviceNo => "deviceno",
+            Link => "link",
+            Device => "device",
+            Flags => "flags",
+            Ea => "ea",
         }
     }
 
@@ -180,15 +301,16 @@ impl FileElement {
             "type" => Some(Type),
             "name" => Some(Name),
             "deviceno" => Some(DeviceNo),
+            "link" => Some(Link),
+            "device" => Some(Device),
+            "flags" => Some(Flags),
+            "ea" => Some(Ea),
             _ => None,
         }
     }
 
     pub fn error(&self) -> Errors {
-        use FileElement::*;
-        match self {
-            _ => Errors::NoFileTypeElement,
-        }
+        Errors::FileElementInvalid(self.name())
     }
 }
 
@@ -216,130 +338,556 @@ impl FileDataElement {
     }
 
     pub fn error(&self) -> Errors {
-        use FileElement::*;
-        match self {
-            _ => Errors::NoFileTypeElement,
-        }
+        Errors::FileDataElementInvalid(self.name())
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum FileType {
     File,
     Directory,
+    Symlink,
+    Hardlink,
+    Fifo,
+    Socket,
     CharacterSpecial,
+    BlockSpecial,
+    Whiteout,
 }
 
 impl FileType {
+    #[deprecated(note = "use `FileType::from_name`")]
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(name: &str) -> Option<FileType> {
+        Self::from_name(name)
+    }
+
+    pub fn from_name(name: &str) -> Option<FileType> {
         use FileType::*;
         match name {
             "file" => Some(File),
             "directory" => Some(Directory),
+            "symlink" => Some(Symlink),
+            "hardlink" => Some(Hardlink),
+            "fifo" => Some(Fifo),
+            "socket" => Some(Socket),
             "character special" => Some(CharacterSpecial),
+            "block special" => Some(BlockSpecial),
+            "whiteout" => Some(Whiteout),
             _ => None,
         }
     }
+
+    /// Name of the type as used in the toc.
+    pub fn name(&self) -> &'static str {
+        use FileType::*;
+        match self {
+            File => "file",
+            Directory => "directory",
+            Symlink => "symlink",
+            Hardlink => "hardlink",
+            Fifo => "fifo",
+            Socket => "socket",
+            CharacterSpecial => "character special",
+            BlockSpecial => "block special",
+            Whiteout => "whiteout",
+        }
+    }
+
+    /// Character used for the type in `ls -l` style listings.
+    pub fn symbol(&self) -> char {
+        use FileType::*;
+        match self {
+            File | Hardlink => '-',
+            Directory => 'd',
+            Symlink => 'l',
+            Fifo => 'p',
+            Socket => 's',
+            CharacterSpecial => 'c',
+            BlockSpecial => 'b',
+            Whiteout => 'w',
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct FileAttr {
+/// What a symlink pointed to when it was archived, from the type attribute
+/// of the `<link>` element.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LinkType {
+    File,
+    Directory,
+    Broken,
+}
+
+impl LinkType {
+    pub fn from_name(name: &str) -> Option<LinkType> {
+        match name {
+            "file" => Some(LinkType::File),
+            "directory" => Some(LinkType::Directory),
+            "broken" => Some(LinkType::Broken),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LinkType::File => "file",
+            LinkType::Directory => "directory",
+            LinkType::Broken => "broken",
+        }
+    }
+}
+
+/// Role of a hardlink, from the link attribute of the `<type>` element. The
+/// original carries the data, the others refer to it by id.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Hardlink {
+    Original,
+    Of(u64),
+}
+
+impl Hardlink {
+    pub fn from_name(name: &str) -> Option<Hardlink> {
+        match name {
+            "original" => Some(Hardlink::Original),
+            id => id.parse().ok().map(Hardlink::Of),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Hardlink::Original => "original".into(),
+            Hardlink::Of(id) => id.to_string(),
+        }
+    }
+}
+
+/// Format permission bits like `ls` does, e.g. `rwxr-xr-x`.
+pub fn mode_string(mode: u32) -> String {
+    let mut string = String::with_capacity(9);
+    for (i, special, set, unset) in &[
+        (6, 0o4000, 's', 'S'),
+        (3, 0o2000, 's', 'S'),
+        (0, 0o1000, 't', 'T'),
+    ] {
+        let bits = (mode >> i) & 0o7;
+        string.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        string.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        string.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => *set,
+            (false, true) => *unset,
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    string
+}
+
+/// One entry of a listing, with its path inside the archive.
+#[derive(Serialize, Debug, Clone)]
+pub struct Listed {
+    pub path: String,
+    #[serde(flatten)]
+    pub entry: FileEntry,
+}
+
+impl Listed {
+    /// List `files` by name, or with `recurse` everything below them by
+    /// path.
+    pub fn list(files: Files, recurse: bool) -> Result<Vec<Listed>, Errors> {
+        let mut listed = Vec::new();
+        Self::list_into(files, recurse, &mut listed)?;
+        Ok(listed)
+    }
+
+    fn list_into(files: Files, recurse: bool, listed: &mut Vec<Listed>) -> Result<(), Errors> {
+        for file in files.iter() {
+            let mut entry = file.entry()?;
+            // hardlinks are listed with the data of their original, if it
+            // can be found.
+            if entry.data.is_none() {
+                entry.data = file.data().unwrap_or(None);
+            }
+
+            if let Some(name) = &entry.name {
+                let path = if recurse && file.path.components().count() != 0 {
+                    format!("{}/{}", file.path.display(), name)
+                } else {
+                    name.clone()
+                };
+                listed.push(Listed { path, entry });
+            }
+
+            if recurse {
+                Self::list_into(file.files(), recurse, listed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A row of an `ls -l` style listing, with the link target of symlinks.
+    pub fn long_row(&self) -> String {
+        let entry = &self.entry;
+        let ftype = entry.ftype.map(|ftype| ftype.symbol()).unwrap_or('?');
+        let mode = entry
+            .mode
+            .map(mode_string)
+            .unwrap_or_else(|| "?????????".into());
+        let owner = |name: &Option<String>, id: Option<u32>| match (name, id) {
+            (Some(name), _) => name.clone(),
+            (None, Some(id)) => id.to_string(),
+            (None, None) => "?".into(),
+        };
+        let size = entry.data.as_ref().map(|data| data.size).unwrap_or(0);
+        let mtime = entry
+            .mtime
+            .map(|mtime| mtime.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "????-??-?? ??:??".into());
+
+        let mut row = format!(
+            "{}{} {:8} {:8} {:>10} {} {}",
+            ftype,
+            mode,
+            owner(&entry.user, entry.uid),
+            owner(&entry.group, entry.gid),
+            size,
+            mtime,
+            self.path
+        );
+        if let Some(link) = &entry.link {
+            row.push_str(" -> ");
+            row.push_str(link);
+        }
+        row
+    }
+}
+
+/// Write a listing, each entry terminated by `terminator`. With `long`,
+/// entries are written as `ls -l` style rows, otherwise just their paths.
+pub fn write_listing<W: Write>(
+    writer: &mut W,
+    listed: &[Listed],
+    long: bool,
+    terminator: char,
+) -> std::io::Result<()> {
+    for listed in listed {
+        if long {
+            write!(writer, "{}{}", listed.long_row(), terminator)?;
+        } else {
+            write!(writer, "{}{}", listed.path, terminator)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checksum of file data, as stored in the toc.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileChecksum {
+    pub alg: ChecksumAlg,
+    pub digest: String,
+}
+
+impl fmt::Display for FileChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.alg.name().unwrap_or("none"), self.digest)
+    }
+}
+
+/// Location and encoding of the data of a file in the heap.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileData {
+    pub offset: u64,
+    pub length: u64,
+    pub size: u64,
+    pub encoding: Encoding,
+    pub archived_checksum: Option<FileChecksum>,
+    pub extracted_checksum: Option<FileChecksum>,
+}
+
+impl FileData {
+    pub fn parse(data: &Element) -> Result<FileData, Errors> {
+        Ok(FileData {
+            offset: Self::parse_u64(data, FileDataElement::Offset)?,
+            length: Self::parse_u64(data, FileDataElement::Length)?,
+            size: Self::parse_u64(data, FileDataElement::Size)?,
+            encoding: data
+                .get_child(FileDataElement::Encoding.name())
+                .and_then(|e| e.attributes.get("style"))
+                .map(|style| Encoding::from_style(style))
+                .unwrap_or_default(),
+            archived_checksum: Self::parse_checksum(data, FileDataElement::ArchivedChecksum),
+            extracted_checksum: Self::parse_checksum(data, FileDataElement::ExtractedChecksum),
+        })
+    }
+
+    fn parse_checksum(data: &Element, element: FileDataElement) -> Option<FileChecksum> {
+        let checksum = data.get_child(element.name())?;
+        Some(FileChecksum {
+            alg: ChecksumAlg::from_name(checksum.attributes.get("style")?),
+            digest: checksum.text.as_ref()?.trim().to_lowercase(),
+        })
+    }
+
+    fn parse_u64(data: &Element, element: FileDataElement) -> Result<u64, Errors> {
+        data.get_child(element.name())
+            .and_then(|e| e.text.as_ref())
+            .ok_or_else(|| element.error())?
+            .parse::<u64>()
+            .map_err(|_| element.error())
+    }
+}
+
+/// Major and minor number of a device file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Device {
+    pub major: u32,
+    pub minor: u32,
+}
+
+/// Extended attribute of a file. Its value is stored in the heap, just like
+/// file data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExtendedAttribute {
+    pub id: Option<u64>,
+    pub name: String,
+    pub data: FileData,
+}
+
+/// All metadata the toc has about a single file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FileEntry {
+    pub id: Option<u64>,
     pub name: Option<String>,
-    pub id: Option<usize>,
     pub ftype: Option<FileType>,
+    pub mode: Option<u32>,
     pub user: Option<String>,
     pub group: Option<String>,
-    pub uid: Option<usize>,
-    pub gid: Option<usize>,
-    pub deviceno: Option<usize>,
-    pub inode: Option<usize>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub ctime: Option<DateTime<Utc>>,
+    pub mtime: Option<DateTime<Utc>>,
+    pub atime: Option<DateTime<Utc>>,
+    pub deviceno: Option<u64>,
+    pub inode: Option<u64>,
+    pub device: Option<Device>,
+    pub link: Option<String>,
+    pub link_type: Option<LinkType>,
+    pub hardlink: Option<Hardlink>,
+    pub flags: Vec<String>,
+    pub xattrs: Vec<ExtendedAttribute>,
+    pub data: Option<FileData>,
 }
 
-impl FileAttr {
+impl FileEntry {
     pub fn new() -> Self {
-        FileAttr {
-            name: None,
-            id: None,
-            ftype: None,
-            user: None,
-            group: None,
-            uid: None,
-            gid: None,
-            deviceno: None,
-            inode: None,
-        }
+        Self::default()
     }
 
-    pub fn parse(data: &Element) -> FileAttr {
-        let mut attrs = FileAttr::new();
+    /// Parse a `<file>` element. Child elements that are not known are
+    /// skipped, but known ones that don't parse are an error.
+    pub fn parse(data: &Element) -> Result<FileEntry, Errors> {
+        let mut entry = FileEntry::new();
+        if let Some(id) = data.attributes.get("id") {
+            entry.id = Some(id.parse().map_err(|_| Errors::FileIdInvalid(id.clone()))?);
+        }
 
         for child in &data.children {
-            let _ = attrs.parse_child(child);
+            if let Some(element) = FileElement::from_name(&child.name) {
+                entry.parse_child(element, child)?;
+            }
         }
 
-        attrs
+        Ok(entry)
     }
 
-    fn parse_child(&mut self, child: &Element) -> Result<(), Errors> {
-        let e = FileElement::from_name(&child.name).ok_or(Errors::NoTocElement)?;
-
+    fn parse_child(&mut self, e: FileElement, child: &Element) -> Result<(), Errors> {
         use FileElement::*;
         match e {
-            Group => Self::parse_text(e, child, &mut self.group),
-            User => Self::parse_text(e, child, &mut self.user),
-            Name => Self::parse_text(e, child, &mut self.name),
-            Type => Self::parse_type(e, child, &mut self.ftype),
-            Data => self.parse_dummy(child),
-            CTime => self.parse_dummy(child),
-            MTime => self.parse_dummy(child),
-            ATime => self.parse_dummy(child),
-            GID => Self::parse_usize(e, child, &mut self.gid),
-            UID => Self::parse_usize(e, child, &mut self.uid),
-            Mode => self.parse_dummy(child),
-            INode => Self::parse_usize(e, child, &mut self.inode),
-            DeviceNo => Self::parse_usize(e, child, &mut self.deviceno),
-        }
-    }
-
-    fn parse_text(
-        element: FileElement,
-        child: &Element,
-        text: &mut Option<String>,
-    ) -> Result<(), Errors> {
-        *text = child.text.clone();
+            Group => self.group = Some(Self::parse_text(e, child)?),
+            User => self.user = Some(Self::parse_text(e, child)?),
+            Name => self.name = Some(Self::parse_text(e, child)?),
+            Link => {
+                self.link = Some(Self::parse_text(e, child)?);
+                self.link_type = Self::parse_attribute(e, child, "type", LinkType::from_name)?;
+            }
+            Type => {
+                self.ftype = Some(Self::parse_type(e, child)?);
+                self.hardlink = Self::parse_attribute(e, child, "link", Hardlink::from_name)?;
+            }
+            Data => self.data = Some(FileData::parse(child)?),
+            CTime => self.ctime = Some(Self::parse_time(e, child)?),
+            MTime => self.mtime = Some(Self::parse_time(e, child)?),
+            ATime => self.atime = Some(Self::parse_time(e, child)?),
+            GID => self.gid = Some(Self::parse_number(e, child)?),
+            UID => self.uid = Some(Self::parse_number(e, child)?),
+            Mode => self.mode = Some(Self::parse_mode(e, child)?),
+            INode => self.inode = Some(Self::parse_number(e, child)?),
+            DeviceNo => self.deviceno = Some(Self::parse_number(e, child)?),
+            Device => self.device = Some(Self::parse_device(e, child)?),
+            Flags => self.flags = child.children.iter().map(|f| f.name.clone()).collect(),
+            Ea => self.xattrs.push(Self::parse_xattr(e, child)?),
+        }
+
         Ok(())
     }
 
-    fn parse_type(
+    fn parse_text(element: FileElement, child: &Element) -> Result<String, Errors> {
+        child.text.clone().ok_or_else(|| element.error())
+    }
+
+    fn parse_attribute<T, F: Fn(&str) -> Option<T>>(
         element: FileElement,
         child: &Element,
-        ftype: &mut Option<FileType>,
-    ) -> Result<(), Errors> {
-        if let Some(text) = &child.text {
-            if let Some(nftype) = FileType::from_str(text) {
-                *ftype = Some(nftype);
-            }
+        name: &str,
+        parse: F,
+    ) -> Result<Option<T>, Errors> {
+        match child.attributes.get(name) {
+            Some(value) => parse(value).map(Some).ok_or_else(|| element.error()),
+            None => Ok(None),
         }
+    }
 
-        Ok(())
+    fn parse_type(element: FileElement, child: &Element) -> Result<FileType, Errors> {
+        let text = Self::parse_text(element, child)?;
+        FileType::from_name(&text).ok_or(Errors::UnknownFileType(text))
     }
 
-    fn parse_usize(
-        element: FileElement,
-        child: &Element,
-        out: &mut Option<usize>,
-    ) -> Result<(), Errors> {
-        let amt = child
-            .text
-            .as_ref()
-            .ok_or(element.error())?
-            .parse::<usize>()
-            .or(Err(element.error()))?;
-        *out = Some(amt);
+    fn parse_number<T: FromStr>(element: FileElement, child: &Element) -> Result<T, Errors> {
+        Self::parse_text(element, child)?
+            .trim()
+            .parse::<T>()
+            .map_err(|_| element.error())
+    }
+
+    /// Modes are stored as octal, like `0644`.
+    fn parse_mode(element: FileElement, child: &Element) -> Result<u32, Errors> {
+        let text = Self::parse_text(element, child)?;
+        u32::from_str_radix(text.trim(), 8).map_err(|_| element.error())
+    }
+
+    fn parse_time(element: FileElement, child: &Element) -> Result<DateTime<Utc>, Errors> {
+        let text = Self::parse_text(element, child)?;
+        DateTime::parse_from_rfc3339(text.trim())
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|_| element.error())
+    }
+
+    fn parse_device(element: FileElement, child: &Element) -> Result<Device, Errors> {
+        let number = |name| {
+            child
+                .get_child(name)
+                .ok_or_else(|| element.error())
+                .and_then(|c| Self::parse_number(element, c))
+        };
+
+        Ok(Device {
+            major: number("major")?,
+            minor: number("minor")?,
+        })
+    }
+
+    fn parse_xattr(element: FileElement, child: &Element) -> Result<ExtendedAttribute, Errors> {
+        let id = match child.attributes.get("id") {
+            Some(id) => Some(id.parse().map_err(|_| element.error())?),
+            None => None,
+        };
+        let name = child
+            .get_child("name")
+            .ok_or_else(|| element.error())
+            .and_then(|name| Self::parse_text(element, name))?;
+
+        Ok(ExtendedAttribute {
+            id,
+            name,
+            data: FileData::parse(child)?,
+        })
+    }
+}
+
+impl FileEntry {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    fn fmt_data(f: &mut fmt::Formatter, prefix: &str, data: &FileData) -> fmt::Result {
+        let field = |name: &str| format!("{}{}", prefix, name);
+        writeln!(f, "{:25}: {}", field("offset"), data.offset)?;
+        writeln!(f, "{:25}: {}", field("length"), data.length)?;
+        writeln!(f, "{:25}: {}", field("size"), data.size)?;
+        writeln!(f, "{:25}: {}", field("encoding"), data.encoding.style())?;
+        if let Some(checksum) = &data.archived_checksum {
+            writeln!(f, "{:25}: {}", field("archived checksum"), checksum)?;
+        }
+        if let Some(checksum) = &data.extracted_checksum {
+            writeln!(f, "{:25}: {}", field("extracted checksum"), checksum)?;
+        }
         Ok(())
     }
+}
 
-    fn parse_dummy(&mut self, child: &Element) -> Result<(), Errors> {
+impl fmt::Display for FileEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(id) = self.id {
+            writeln!(f, "{:25}: {}", "id", id)?;
+        }
+        if let Some(name) = &self.name {
+            writeln!(f, "{:25}: {}", "name", name)?;
+        }
+        if let Some(ftype) = self.ftype {
+            writeln!(f, "{:25}: {}", "type", ftype.name())?;
+        }
+        if let Some(hardlink) = &self.hardlink {
+            writeln!(f, "{:25}: {}", "hardlink", hardlink.name())?;
+        }
+        if let Some(link) = &self.link {
+            match self.link_type {
+                Some(link_type) => writeln!(f, "{:25}: {} ({})", "link", link, link_type.name())?,
+                None => writeln!(f, "{:25}: {}", "link", link)?,
+            }
+        }
+        if let Some(mode) = self.mode {
+            writeln!(f, "{:25}: {:04o} ({})", "mode", mode, mode_string(mode))?;
+        }
+        for (name, owner, id) in &[
+            ("user", &self.user, self.uid),
+            ("group", &self.group, self.gid),
+        ] {
+            match (owner, id) {
+                (Some(owner), Some(id)) => writeln!(f, "{:25}: {} ({})", name, owner, id)?,
+                (Some(owner), None) => writeln!(f, "{:25}: {}", name, owner)?,
+                (None, Some(id)) => writeln!(f, "{:25}: {}", name, id)?,
+                (None, None) => {}
+            }
+        }
+        for (name, time) in &[
+            ("ctime", self.ctime),
+            ("mtime", self.mtime),
+            ("atime", self.atime),
+        ] {
+            if let Some(time) = time {
+                writeln!(f, "{:25}: {}", name, time.to_rfc3339())?;
+            }
+        }
+        if let Some(inode) = self.inode {
+            writeln!(f, "{:25}: {}", "inode", inode)?;
+        }
+        if let Some(deviceno) = self.deviceno {
+            writeln!(f, "{:25}: {}", "deviceno", deviceno)?;
+        }
+        if let Some(device) = self.device {
+            writeln!(f, "{:25}: {}, {}", "device", device.major, device.minor)?;
+        }
+        if !self.flags.is_empty() {
+            writeln!(f, "{:25}: {}", "flags", self.flags.join(", "))?;
+        }
+        if let Some(data) = &self.data {
+            Self::fmt_data(f, "data ", data)?;
+        }
+        for xattr in &self.xattrs {
+            writeln!(f, "{:25}: {}", "xattr", xattr.name)?;
+            Self::fmt_data(f, "xattr ", &xattr.data)?;
+        }
         Ok(())
     }
 }
@@ -347,63 +895,163 @@ impl FileAttr {
 /// File object.
 #[derive(Debug, Clone)]
 pub struct File<'a, 'b> {
+    root: &'a Element,
     data: &'a Element,
-    pub path: &'b Path,
+    /// Path of the directory containing this file.
+    pub path: Cow<'b, Path>,
 }
 
 impl<'a, 'b> File<'a, 'b> {
-    pub fn new(element: &'a Element, path: &'b Path) -> File<'a, 'b> {
+    /// Wrap the `<file>` element `element`, with `root` being the `<toc>` it
+    /// belongs to.
+    pub fn new(root: &'a Element, element: &'a Element, path: &'b Path) -> File<'a, 'b> {
         File {
+            root,
             data: element,
-            path: path,
+            path: Cow::Borrowed(path),
         }
     }
 
     pub fn files(&self) -> Files<'a> {
         let mut path = self.path.to_path_buf();
-        let attrs = self.attrs();
         // TODO: what if no name?
-        if let Some(name) = attrs.name {
+        if let Some(name) = self.name() {
             path.push(name)
         }
 
         Files {
+            root: self.root,
             data: self.data,
-            path: path,
+            path,
+        }
+    }
+
+    /// Name of the file, without parsing the rest of the entry.
+    pub fn name(&self) -> Option<&'a str> {
+        self.data
+            .get_child(FileElement::Name.name())
+            .and_then(|name| name.text.as_deref())
+    }
+
+    pub fn entry(&self) -> Result<FileEntry, Errors> {
+        FileEntry::parse(self.data)
+    }
+
+    /// Extended attributes of this file.
+    pub fn xattrs(&self) -> Result<Vec<ExtendedAttribute>, Errors> {
+        self.entry().map(|entry| entry.xattrs)
+    }
+
+    /// Location of the data of this file. Hardlinks without data of their
+    /// own resolve to the data of their original.
+    pub fn data(&self) -> Result<Option<FileData>, Errors> {
+        let entry = self.entry()?;
+        match (entry.data, entry.hardlink) {
+            (Some(data), _) => Ok(Some(data)),
+            (None, Some(Hardlink::Of(id))) => self
+                .hardlink_original()
+                .ok_or(Errors::HardlinkOriginalMissing(id))?
+                .entry()
+                .map(|original| original.data),
+            (None, _) => Ok(None),
         }
     }
 
-    pub fn attrs(&self) -> FileAttr {
-        FileAttr::parse(&self.data)
+    /// The original a hardlink refers to, if this is a link to another file.
+    pub fn hardlink_original(&self) -> Option<File<'a, 'static>> {
+        let id = match self.entry().ok()?.hardlink? {
+            Hardlink::Of(id) => id,
+            Hardlink::Original => return None,
+        };
+
+        Files {
+            root: self.root,
+            data: self.root,
+            path: PathBuf::new(),
+        }
+        .find_id(id)
     }
 }
 
 /// Iterator over the files (in the current level).
 #[derive(Debug, Clone)]
 pub struct Files<'a> {
+    root: &'a Element,
     data: &'a Element,
     path: PathBuf,
 }
 
 impl<'a> Files<'a> {
-    pub fn iter(&self) -> FilesIter {
+    pub fn iter(&self) -> FilesIter<'a, '_> {
         FilesIter {
+            root: self.root,
             data: self.data,
             path: &self.path,
             pos: 0,
         }
     }
 
-    pub fn find(&self, path: &Path) -> Option<Files> {
-        let mut files: Option<Files> = Some(self.clone());
+    /// Find a file by its path relative to this level. Leading slashes and
+    /// `.` components are ignored, `..` goes up one level.
+    pub fn find(&self, path: &Path) -> Option<File<'a, 'static>> {
+        let mut components: Vec<&OsStr> = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => components.push(name),
+                Component::ParentDir => {
+                    components.pop()?;
+                }
+                _ => {}
+            }
+        }
+
+        let (last, parents) = components.split_last()?;
+        let mut files = self.clone();
+        for name in parents {
+            files = files.child(name)?.files();
+        }
+
+        files.child(last).map(|file| File {
+            root: file.root,
+            data: file.data,
+            path: Cow::Owned(files.path.clone()),
+        })
+    }
+
+    /// Find a file at this level by name.
+    fn child(&self, name: &OsStr) -> Option<File<'a, '_>> {
+        self.iter()
+            .find(|file| file.name().map(OsStr::new) == Some(name))
+    }
 
-        files
+    /// Find a file by its id, anywhere below this level.
+    pub fn find_id(&self, id: u64) -> Option<File<'a, 'static>> {
+        self.find_id_str(&id.to_string())
+    }
+
+    fn find_id_str(&self, id: &str) -> Option<File<'a, 'static>> {
+        for file in self.iter() {
+            if file.data.attributes.get("id").map(String::as_str) == Some(id) {
+                return Some(File {
+                    root: file.root,
+                    data: file.data,
+                    path: Cow::Owned(self.path.clone()),
+                });
+            }
+
+            if let Some(found) = file.files().find_id_str(id) {
+                return Some(found);
+            }
+        }
+
+        None
     }
 }
 
 /// Iterator over the files
 #[derive(Debug, Clone)]
 pub struct FilesIter<'a, 'b> {
+    root: &'a Element,
     data: &'a Element,
     path: &'b Path,
     pos: usize,
@@ -416,8 +1064,9 @@ impl<'a, 'b> Iterator for FilesIter<'a, 'b> {
             if child.name == "file" {
                 self.pos = i + 1;
                 return Some(File {
+                    root: self.root,
                     data: child,
-                    path: self.path,
+                    path: Cow::Borrowed(self.path),
                 });
             }
         }
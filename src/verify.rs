@@ -0,0 +1,148 @@
+use crate::checksum::{to_hex, Checksum, ChecksumReader};
+use crate::error::describe;
+use crate::toc::{FileChecksum, FileData, Files};
+use crate::Archive;
+use crate::Error;
+use std::io::{Read, Seek};
+use thiserror::Error;
+
+/// Problem with the data of a single file.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum Failure {
+    #[error("{path}: archived checksum mismatch, expected {expected}, got {actual}.")]
+    ArchivedChecksum {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("{path}: extracted checksum mismatch, expected {expected}, got {actual}.")]
+    ExtractedChecksum {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("{path}: {error}")]
+    Unreadable { path: String, error: String },
+}
+
+/// Checks the data and extended attributes of every file against the
+/// checksums in the toc.
+pub struct Verifier<'a, R: Read + Seek> {
+    archive: &'a Archive<R>,
+    failures: Vec<Failure>,
+}
+
+impl<'a, R: Read + Seek> Verifier<'a, R> {
+    pub fn new(archive: &'a Archive<R>) -> Self {
+        Verifier {
+            archive,
+            failures: Vec::new(),
+        }
+    }
+
+    /// Verify all files, returning every failure found. Anything going wrong
+    /// with a single file is reported as a failure rather than an error.
+    pub fn verify(mut self) -> Result<Vec<Failure>, Error> {
+        let files = self.archive.toc().files()?;
+        self.verify_files(&files)?;
+        Ok(self.failures)
+    }
+
+    fn verify_files(&mut self, files: &Files) -> Result<(), Error> {
+        for file in files.iter() {
+            let path = file.path.join(file.name().unwrap_or(""));
+            let path = path.display().to_string();
+            let result = file.entry().map_err(Error::from).and_then(|entry| {
+                if let Some(data) = &entry.data {
+                    self.verify_data(data, path.clone())?;
+                }
+                for xattr in &entry.xattrs {
+                    let name = format!("{} (extended attribute {})", path, xattr.name);
+                    self.verify_data(&xattr.data, name)?;
+                }
+                Ok(())
+            });
+
+            if let Err(error) = result {
+                self.failures.push(Failure::Unreadable {
+                    path,
+                    error: describe(&error),
+                });
+            }
+
+            self.verify_files(&file.files())?;
+        }
+
+        Ok(())
+    }
+
+    /// Hashes the archived bytes as they are read from the heap and the
+    /// extracted bytes as they come out of the decoder, in one pass.
+    fn verify_data(&mut self, data: &FileData, path: String) -> Result<(), Error> {
+        let archived = Self::checksum(&data.archived_checksum)?;
+        let mut extracted = Self::checksum(&data.extracted_checksum)?;
+
+        let heap = self.archive.open_heap(data.offset, data.length)?;
+        let mut reader = ChecksumReader {
+            reader: heap,
+            checksum: archived,
+        };
+
+        let decoded = Self::decode(data, &mut reader, &mut extracted);
+
+        // the decoder might not have consumed everything.
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+
+        if let (Some(expected), Some(checksum)) = (&data.archived_checksum, reader.checksum) {
+            let actual = to_hex(&checksum.finish());
+            if actual != expected.digest {
+                self.failures.push(Failure::ArchivedChecksum {
+                    path: path.clone(),
+                    expected: expected.digest.clone(),
+                    actual,
+                });
+            }
+        }
+
+        if let Err(error) = decoded {
+            self.failures.push(Failure::Unreadable {
+                path,
+                error: describe(&error),
+            });
+            return Ok(());
+        }
+
+        if let (Some(expected), Some(checksum)) = (&data.extracted_checksum, extracted) {
+            let actual = to_hex(&checksum.finish());
+            if actual != expected.digest {
+                self.failures.push(Failure::ExtractedChecksum {
+                    path,
+                    expected: expected.digest.clone(),
+                    actual,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn decode<T: Read>(
+        data: &FileData,
+        reader: T,
+        checksum: &mut Option<Checksum>,
+    ) -> Result<(), Error> {
+        let mut decoder = data.encoding.decoder(reader)?;
+        match checksum {
+            Some(checksum) => std::io::copy(&mut decoder, checksum)?,
+            None => std::io::copy(&mut decoder, &mut std::io::sink())?,
+        };
+        Ok(())
+    }
+
+    fn checksum(checksum: &Option<FileChecksum>) -> Result<Option<Checksum>, Error> {
+        match checksum {
+            Some(checksum) => Ok(Some(Checksum::new(&checksum.alg)?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Reading and writing extended attributes of files on disk. Only Linux is
+//! supported, elsewhere files have no extended attributes.
+
+use std::io::Result;
+use std::path::Path;
+
+/// All extended attributes of a file (not following symlinks).
+#[cfg(target_os = "linux")]
+pub fn list(path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let path = c_path(path)?;
+    let names = read_buffer(|buf, len| unsafe { libc::llistxattr(path.as_ptr(), buf, len) })?;
+
+    let mut xattrs = Vec::new();
+    for name in names.split(|c| *c == 0).filter(|name| !name.is_empty()) {
+        let name = std::ffi::CString::new(name)?;
+        let value = read_buffer(|buf, len| unsafe {
+            libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf as *mut _, len)
+        })?;
+        xattrs.push((name.to_string_lossy().into_owned(), value));
+    }
+
+    Ok(xattrs)
+}
+
+/// Set an extended attribute of a file (not following symlinks).
+#[cfg(target_os = "linux")]
+pub fn set(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    let path = c_path(path)?;
+    let name = std::ffi::CString::new(name)?;
+    let result = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const _,
+            value.len(),
+            0,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Whether an error means the file system (or namespace of the name) does
+/// not support extended attributes.
+#[cfg(target_os = "linux")]
+pub fn is_unsupported(error: &std::io::Error) -> bool {
+    error.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+/// Whether an error means only privileged processes may set the attribute
+/// `name`, as for the `security` and `trusted` namespaces.
+#[cfg(target_os = "linux")]
+pub fn is_privileged(error: &std::io::Error, name: &str) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EPERM) | Some(libc::EACCES))
+        && (name.starts_with("security.") || name.starts_with("trusted."))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn list(_path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set(_path: &Path, _name: &str, _value: &[u8]) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_unsupported(_error: &std::io::Error) -> bool {
+    false
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_privileged(_error: &std::io::Error, _name: &str) -> bool {
+    false
+}
+
+/// Path as a C string, for passing to libc.
+#[cfg(unix)]
+pub(crate) fn c_path(path: &Path) -> Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::ffi::CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Calls `f` first to get the size, then again to fill the buffer. The size
+/// can change between calls, so this retries on `ERANGE`.
+#[cfg(target_os = "linux")]
+fn read_buffer<F: Fn(*mut libc::c_char, usize) -> libc::ssize_t>(f: F) -> Result<Vec<u8>> {
+    loop {
+        let size = match f(std::ptr::null_mut(), 0) {
+            -1 => return Err(std::io::Error::last_os_error()),
+            size => size as usize,
+        };
+
+        let mut buffer = vec![0u8; size];
+        match f(buffer.as_mut_ptr() as *mut _, size) {
+            -1 => {
+                let error = std::io::Error::last_os_error();
+                if error.raw_os_error() != Some(libc::ERANGE) {
+                    return Err(error);
+                }
+            }
+            read => {
+                buffer.truncate(read as usize);
+                return Ok(buffer);
+            }
+        }
+    }
+}